@@ -0,0 +1,719 @@
+//src/model.rs before incremental group tracking and position hashes, unchanged apart from its tests
+use std::{
+    fmt,
+    error,
+    collections::HashSet,
+    time::SystemTime
+};
+use serde::{Serialize, Deserialize};
+use crate::timer::TimeControl;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub board_size: u32,
+    pub komi: f32,
+    pub handicap: u32,
+    pub placement: Placement,
+    pub ruleset: Ruleset,
+    pub time_control: TimeControl,
+    //Display names
+    #[serde(default)]
+    pub black_player: String,
+    #[serde(default)]
+    pub white_player: String,
+    //Rated games get no engine hints while in progress
+    #[serde(default)]
+    pub rated: bool
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stone {
+    Black,
+    White,
+    Empty
+}
+
+impl From<Stone> for &str {
+    fn from(value: Stone) -> Self {
+        match value {
+            Stone::Black => "black",
+            Stone::White => "white",
+            Stone::Empty => "empty"
+        }
+    }
+}
+
+impl Stone {
+    pub fn opponent(self) -> Stone {
+        match self {
+            Stone::Black => Stone::White,
+            Stone::White => Stone::Black,
+            Stone::Empty => Stone::Empty
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Turn {
+    Handicap,
+    Black,
+    White,
+    Scoring,
+    End
+}
+
+impl From<Turn> for &str {
+    fn from(value: Turn) -> Self {
+        match value {
+            Turn::Handicap => "handicap",
+            Turn::Black => "black",
+            Turn::White => "white",
+            Turn::Scoring => "scoring",
+            Turn::End => "end"
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scoring {
+    //Stones and surrounded points
+    Area,
+    //Surrounded points and prisoners
+    Territory
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "lowercase")]
+pub enum GameResult {
+    Score {winner: Stone, margin: f32},
+    Draw,
+    Resignation {winner: Stone},
+    Timeout {winner: Stone},
+    //Player abandoned the game
+    Forfeit {winner: Stone},
+    //Game expired before it finished
+    Aborted,
+    //Engine playing this side stopped working
+    #[serde(rename = "engine-failure")]
+    EngineFailure {stone: Stone}
+}
+
+impl fmt::Display for GameResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let color = |stone: &Stone| match stone {
+            Stone::Black => "B",
+            _ => "W"
+        };
+        match self {
+            Self::Score {winner, margin} => write!(f, "{}+{}", color(winner), margin),
+            Self::Draw => write!(f, "Draw"),
+            Self::Resignation {winner} => write!(f, "{}+R", color(winner)),
+            Self::Timeout {winner} => write!(f, "{}+T", color(winner)),
+            Self::Forfeit {winner} => write!(f, "{}+F", color(winner)),
+            Self::Aborted | Self::EngineFailure {..} => write!(f, "Void")
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Placement {
+    //Black chooses where handicap stones go
+    Free,
+    //Handicap stones go on the star points
+    Fixed
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Ko {
+    //No immediate recapture
+    Simple,
+    //No repetition of a board position
+    Positional,
+    //No repetition of a board position with the same player to move
+    Situational
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ruleset {
+    Chinese,
+    Japanese,
+    Aga,
+    NewZealand,
+    TrompTaylor
+}
+
+impl From<Ruleset> for &str {
+    fn from(value: Ruleset) -> Self {
+        match value {
+            Ruleset::Chinese => "Chinese",
+            Ruleset::Japanese => "Japanese",
+            Ruleset::Aga => "AGA",
+            Ruleset::NewZealand => "New Zealand",
+            Ruleset::TrompTaylor => "Tromp-Taylor"
+        }
+    }
+}
+
+impl Ruleset {
+    pub fn suicide(self) -> bool {
+        matches!(self, Ruleset::NewZealand | Ruleset::TrompTaylor)
+    }
+    pub fn ko(self) -> Ko {
+        match self {
+            Ruleset::Japanese => Ko::Simple,
+            Ruleset::Chinese | Ruleset::TrompTaylor => Ko::Positional,
+            Ruleset::Aga | Ruleset::NewZealand => Ko::Situational
+        }
+    }
+    pub fn scoring(self) -> Scoring {
+        match self {
+            Ruleset::Japanese => Scoring::Territory,
+            _ => Scoring::Area
+        }
+    }
+    pub fn pass_stones(self) -> bool {
+        self == Ruleset::Aga
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Handicap(Vec<usize>),
+    Play(usize),
+    Pass,
+    Resign
+}
+
+#[derive(Clone, Debug)]
+pub struct Move {
+    pub action: Action,
+    pub stone: Stone,
+    pub captures: Vec<usize>,
+    pub time: SystemTime
+}
+
+impl Move {
+    fn new(action: Action, stone: Stone, captures: Vec<usize>) -> Move {
+        Move {
+            action,
+            stone,
+            captures,
+            time: SystemTime::now()
+        }
+    }
+}
+
+fn neighbors(n: usize, board: &[Stone], pos: usize) -> Vec<usize> {
+    let mut result = Vec::<usize>::new();
+    if pos >= 1 && !pos.is_multiple_of(n) {
+        result.push(pos - 1);
+    }
+    if pos + 1 < board.len() && pos % n < n - 1 {
+        result.push(pos + 1);
+    }
+    if pos >= n {
+        result.push(pos - n);
+    }
+    if pos + n < board.len() {
+        result.push(pos + n);
+    }
+    result
+}
+
+fn connected_group(n: usize, board: &[Stone], pos: usize) -> Vec<usize> {
+    debug_assert!(pos < board.len());
+    let mut result = Vec::<usize>::new();
+    let color = board[pos];
+    let mut seen = vec![false; board.len()];
+    seen[pos] = true;
+    let mut stack = vec![pos];
+    while let Some(pos) = stack.pop() {
+        result.push(pos);
+        for neighbor in neighbors(n, board, pos) {
+            if board[neighbor] == color && !seen[neighbor] {
+                seen[neighbor] = true;
+                stack.push(neighbor);
+            }
+        }
+    }
+    result
+}
+
+fn liberty(n: usize, board: &[Stone], group: &[usize]) -> bool {
+    for &pos in group {
+        if board[pos] == Stone::Empty {
+            return true;
+        }
+        for neighbor in neighbors(n, board, pos) {
+            if board[neighbor] == Stone::Empty {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn star_points(n: usize, count: u32) -> Option<Vec<usize>> {
+    if n < 7 || (count > 4 && n.is_multiple_of(2)) {
+        return None
+    }
+    //Distance from the edge
+    let edge = if n >= 13 {3} else {2};
+    let (near, far, middle) = (edge, n - 1 - edge, n / 2);
+    let point = |x: usize, y: usize| n * y + x;
+    let mut points = vec![point(far, near), point(near, far)];
+    if count >= 3 {
+        points.push(point(far, far));
+    }
+    if count >= 4 {
+        points.push(point(near, near));
+    }
+    if count >= 6 {
+        points.push(point(near, middle));
+        points.push(point(far, middle));
+    }
+    if count >= 8 {
+        points.push(point(middle, near));
+        points.push(point(middle, far));
+    }
+    if count % 2 == 1 && count >= 5 {
+        points.push(point(middle, middle));
+    }
+    Some(points)
+}
+
+fn place_stone(n: usize, board: &mut [Stone], stone: Stone, pos: usize) -> Vec<usize> {
+    board[pos] = stone;
+    let mut captures = Vec::new();
+    //Capture
+    for neighbor in neighbors(n, board, pos) {
+        if board[neighbor] != stone && board[neighbor] != Stone::Empty {
+            let group = connected_group(n, board, neighbor);
+            if !liberty(n, board, &group) {
+                for &pos in &group {
+                    board[pos] = Stone::Empty;
+                }
+                captures.extend(group);
+            }
+        }
+    }
+    //Self-capture
+    let group = connected_group(n, board, pos);
+    if !liberty(n, board, &group) {
+        for &pos in &group {
+            board[pos] = Stone::Empty;
+        }
+        captures.extend(group);
+    }
+    captures
+}
+
+pub enum GameError {
+    Creation,
+    Handicap,
+    Play
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Creation => writeln!(f, "Creation error"),
+            Self::Handicap => writeln!(f, "Handicap error"),
+            Self::Play => writeln!(f, "Play error"),
+        }
+    }
+}
+
+impl fmt::Debug for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Creation => writeln!(f, "Creation error"),
+            Self::Handicap => writeln!(f, "Handicap error"),
+            Self::Play => writeln!(f, "Play error"),
+        }
+    }
+}
+
+impl error::Error for GameError {}
+
+#[derive(Clone)]
+pub struct Game {
+    //Settings
+    pub board_size: usize,
+    pub komi: f32,
+    pub handicap: u32,
+    pub placement: Placement,
+    //Game state
+    pub board: Vec<Stone>,
+    pub history: Vec<Vec<Stone>>,
+    pub moves: Vec<Move>,
+    pub valid_moves: Vec<bool>,
+    pub turn: Turn,
+    pub passes: u32,
+    pub ruleset: Ruleset,
+    //Scoring
+    pub captures: [u32; 2],
+    pub dead: Vec<bool>,
+    pub accepted: [bool; 2],
+    pub black_score: f32,
+    pub white_score: f32,
+    pub result: Option<GameResult>,
+    //Result suggested by the scoring engine
+    pub estimate: Option<GameResult>
+}
+
+impl Game {
+    pub fn new(
+        board_size: usize,
+        komi: f32,
+        handicap: u32,
+        placement: Placement,
+        ruleset: Ruleset
+    ) -> Result<Game, GameError> {
+        //Komi is counted in half points
+        if !komi.is_finite() || (komi * 2.0).fract() != 0.0 {
+            return Err(GameError::Creation)
+        }
+        if (5..=19).contains(&board_size) && (1..=9).contains(&handicap) {
+            let tile_count = board_size * board_size;
+            let board = vec![Stone::Empty; tile_count];
+            let mut game = Game {
+                board_size,
+                komi,
+                handicap,
+                placement,
+                board: board.clone(),
+                history: vec![board.clone()],
+                moves: Vec::new(),
+                valid_moves: vec![true; tile_count],
+                turn: if handicap == 1 {
+                    Turn::Black
+                } else {
+                    Turn::Handicap
+                },
+                passes: 0,
+                ruleset,
+                captures: [0, 0],
+                dead: vec![false; tile_count],
+                accepted: [false, false],
+                black_score: 0.0,
+                white_score: komi,
+                result: None,
+                estimate: None
+            };
+            if placement == Placement::Fixed && handicap > 1 {
+                let points = star_points(board_size, handicap).ok_or(GameError::Handicap)?;
+                game.play_handicap(Stone::Black, &points)?;
+            }
+            Ok(game)
+        } else {
+            Err(GameError::Handicap)
+        }
+    }
+    pub fn play_handicap(&mut self, stone: Stone, positions: &[usize]) -> Result<(), GameError> {
+        if stone == Stone::Black && self.turn == Turn::Handicap {
+            let set: HashSet<usize> = positions.iter().copied()
+                .filter(|&x| x < self.board.len())
+                .collect();
+            if set.len() == positions.len() && positions.len() <= self.handicap as usize {
+                for &i in positions {
+                    self.board[i] = stone;
+                }
+                self.history.push(self.board.clone());
+                self.moves.push(Move::new(Action::Handicap(positions.to_vec()), stone, Vec::new()));
+                self.turn = Turn::White;
+                self.valid_moves = self.next_moves(Stone::White)?;
+                Ok(())
+            } else {
+                Err(GameError::Handicap)
+            }
+        } else {
+            Err(GameError::Handicap)
+        }
+    }
+    fn repeats(&self, board: &[Stone], stone: Stone) -> bool {
+        match self.ruleset.ko() {
+            Ko::Simple => self.history.len() >= 2
+                && self.history[self.history.len() - 2] == board,
+            Ko::Positional => self.history.iter().any(|entry| entry == board),
+            Ko::Situational => self.history.iter().enumerate().any(|(i, entry)| {
+                //The initial position has Black to move
+                let mover = if i == 0 {Stone::White} else {self.moves[i - 1].stone};
+                mover == stone && entry == board
+            })
+        }
+    }
+    fn next_moves(&self, stone: Stone) -> Result<Vec<bool>, GameError> {
+        if stone == Stone::Black || stone == Stone::White {
+            let mut moves = vec![true; self.board.len()];
+            for (pos, legal) in moves.iter_mut().enumerate() {
+                if self.board[pos] == Stone::Empty {
+                    let mut board = self.board.clone();
+                    let captures = place_stone(self.board_size, &mut board, stone, pos);
+                    let suicide = captures.contains(&pos);
+                    *legal = (!suicide || self.ruleset.suicide())
+                        && (captures.is_empty() || !self.repeats(&board, stone));
+                } else {
+                    *legal = false;
+                }
+            }
+            Ok(moves)
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn play(&mut self, stone: Stone, pos: usize) -> Result<(), GameError> {
+        //Conditions
+        if pos < self.board.len()
+            && (
+                (stone == Stone::Black && self.turn == Turn::Black)
+                || (stone == Stone::White && self.turn == Turn::White)
+            ) && self.valid_moves[pos] {
+            //Place stone
+            let captures = place_stone(self.board_size, &mut self.board, stone, pos);
+            //Prisoners (self-captured stones go to the opponent)
+            let prisoners = captures.len() as u32;
+            match (stone, captures.contains(&pos)) {
+                (Stone::Black, false) | (Stone::White, true) => self.captures[0] += prisoners,
+                _ => self.captures[1] += prisoners
+            }
+            self.history.push(self.board.clone());
+            self.moves.push(Move::new(Action::Play(pos), stone, captures));
+            //Advance turn
+            self.turn = match self.turn {
+                Turn::Black => Turn::White,
+                Turn::White => Turn::Black,
+                _ => return Err(GameError::Play)
+            };
+            self.passes = 0;
+            //Generate next valid moves
+            let next_stone = match stone {
+                Stone::Black => Stone::White,
+                Stone::White => Stone::Black,
+                _ => return Err(GameError::Play)
+            };
+            self.valid_moves = self.next_moves(next_stone).unwrap();
+            //Scoring
+            [self.black_score, self.white_score] = self.score();
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn pass(&mut self, stone: Stone) -> Result<(), GameError> {
+        if (stone == Stone::Black && self.turn == Turn::Black)
+            || (stone == Stone::White && self.turn == Turn::White) {
+            self.passes += 1;
+            //Passing hands a prisoner to the opponent
+            if self.ruleset.pass_stones() {
+                match stone {
+                    Stone::Black => self.captures[1] += 1,
+                    _ => self.captures[0] += 1
+                }
+            }
+            self.history.push(self.board.clone());
+            self.moves.push(Move::new(Action::Pass, stone, Vec::new()));
+            //With pass stones, White must pass last
+            if self.passes >= 2 && (!self.ruleset.pass_stones() || stone == Stone::White) {
+                self.turn = Turn::Scoring;
+                self.dead = vec![false; self.board.len()];
+                self.accepted = [false, false];
+                self.estimate = None;
+                self.valid_moves = vec![false; self.board.len()];
+            } else {
+                match stone {
+                    Stone::Black => {
+                        self.valid_moves = self.next_moves(Stone::White).unwrap();
+                        self.turn = Turn::White;
+                    },
+                    Stone::White => {
+                        self.valid_moves = self.next_moves(Stone::Black).unwrap();
+                        self.turn = Turn::Black;
+                    },
+                    _ => ()
+                }
+            }
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    fn end(&mut self, result: GameResult) {
+        self.result = Some(result);
+        self.turn = Turn::End;
+    }
+    fn to_move(&self, stone: Stone) -> bool {
+        match stone {
+            Stone::Black => self.turn == Turn::Black || self.turn == Turn::Handicap,
+            Stone::White => self.turn == Turn::White,
+            Stone::Empty => false
+        }
+    }
+    pub fn resign(&mut self, stone: Stone) -> Result<(), GameError> {
+        if self.to_move(stone) {
+            self.moves.push(Move::new(Action::Resign, stone, Vec::new()));
+            self.end(GameResult::Resignation {winner: stone.opponent()});
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn timeout(&mut self, stone: Stone) -> Result<(), GameError> {
+        if self.to_move(stone) {
+            self.end(GameResult::Timeout {winner: stone.opponent()});
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn forfeit(&mut self, stone: Stone) -> Result<(), GameError> {
+        if stone != Stone::Empty && matches!(self.turn, Turn::Handicap | Turn::Black | Turn::White) {
+            self.end(GameResult::Forfeit {winner: stone.opponent()});
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn engine_failure(&mut self, stone: Stone) -> Result<(), GameError> {
+        if stone != Stone::Empty && self.turn != Turn::End {
+            self.end(GameResult::EngineFailure {stone});
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn abort(&mut self) -> Result<(), GameError> {
+        if self.turn != Turn::End {
+            self.end(GameResult::Aborted);
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn mark(&mut self, stone: Stone, pos: usize) -> Result<(), GameError> {
+        if self.turn == Turn::Scoring
+            && stone != Stone::Empty
+            && pos < self.board.len()
+            && self.board[pos] != Stone::Empty {
+            //Toggle the whole group
+            let dead = !self.dead[pos];
+            for pos in connected_group(self.board_size, &self.board, pos) {
+                self.dead[pos] = dead;
+            }
+            self.accepted = [false, false];
+            [self.black_score, self.white_score] = self.score();
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    //Mark the stones an engine considers dead, for the players to confirm
+    pub fn suggest(&mut self, dead: &[usize], estimate: GameResult) -> Result<(), GameError> {
+        if self.turn == Turn::Scoring && dead.iter().all(|&pos| pos < self.board.len()) {
+            for &pos in dead {
+                if self.board[pos] != Stone::Empty {
+                    self.dead[pos] = true;
+                }
+            }
+            self.estimate = Some(estimate);
+            self.accepted = [false, false];
+            [self.black_score, self.white_score] = self.score();
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn accept(&mut self, stone: Stone) -> Result<(), GameError> {
+        if self.turn == Turn::Scoring {
+            match stone {
+                Stone::Black => self.accepted[0] = true,
+                Stone::White => self.accepted[1] = true,
+                Stone::Empty => return Err(GameError::Play)
+            }
+            if self.accepted[0] && self.accepted[1] {
+                [self.black_score, self.white_score] = self.score();
+                let margin = self.black_score - self.white_score;
+                self.end(if margin > 0.0 {
+                    GameResult::Score {winner: Stone::Black, margin}
+                } else if margin < 0.0 {
+                    GameResult::Score {winner: Stone::White, margin: -margin}
+                } else {
+                    GameResult::Draw
+                });
+            }
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn last_move(&self) -> Option<usize> {
+        match self.moves.last() {
+            Some(Move {action: Action::Play(pos), ..}) => Some(*pos),
+            _ => None
+        }
+    }
+    pub fn score(&self) -> [f32; 2] {
+        //Dead stones are removed
+        let board: Vec<Stone> = self.board.iter().zip(&self.dead)
+            .map(|(&stone, &dead)| if dead {Stone::Empty} else {stone})
+            .collect();
+        //Determine territory
+        let mut territory = [0, 0];
+        let mut seen = vec![false; board.len()];
+        for pos in 0..board.len() {
+            if board[pos] == Stone::Empty && !seen[pos] {
+                let group = connected_group(self.board_size, &board, pos);
+                let mut bordered = [false, false];
+                for &pos in &group {
+                    seen[pos] = true;
+                    for neighbor in neighbors(self.board_size, &board, pos) {
+                        match board[neighbor] {
+                            Stone::Black => bordered[0] = true,
+                            Stone::White => bordered[1] = true,
+                            _ => ()
+                        }
+                    }
+                }
+                if bordered[0] && !bordered[1] {
+                    territory[0] += group.len() as u32;
+                } else if !bordered[0] && bordered[1] {
+                    territory[1] += group.len() as u32;
+                }
+            }
+        }
+        let points = match self.ruleset.scoring() {
+            Scoring::Area => {
+                //Count stones
+                let mut counts = [0, 0];
+                for stone in &board {
+                    match stone {
+                        Stone::Black => counts[0] += 1,
+                        Stone::White => counts[1] += 1,
+                        _ => ()
+                    }
+                }
+                [territory[0] + counts[0], territory[1] + counts[1]]
+            },
+            Scoring::Territory => {
+                //Count prisoners
+                let mut prisoners = self.captures;
+                for (pos, &dead) in self.dead.iter().enumerate() {
+                    match self.board[pos] {
+                        Stone::Black if dead => prisoners[1] += 1,
+                        Stone::White if dead => prisoners[0] += 1,
+                        _ => ()
+                    }
+                }
+                [territory[0] + prisoners[0], territory[1] + prisoners[1]]
+            }
+        };
+        //Komi goes to White
+        [points[0] as f32, points[1] as f32 + self.komi]
+    }
+}
+
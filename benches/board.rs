@@ -0,0 +1,71 @@
+use casual_go::model::{Game, Placement, Ruleset, Stone, Turn};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+/*
+    Legal move generation after every move of a game, compared with the
+    previous model, which tries each empty point on a copy of the board
+    and compares the result with every earlier position
+*/
+
+#[allow(dead_code)]
+#[path = "baseline/model.rs"]
+mod baseline;
+
+//For the baseline model's imports
+mod timer {
+    pub use casual_go::timer::TimeControl;
+}
+
+//Random legal moves until both players pass or the move limit
+fn record(n: usize, length: usize) -> Vec<(Stone, Option<usize>)> {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut game = Game::new(n, 7.5, 1, Placement::Free, Ruleset::Chinese).unwrap();
+    let mut moves = Vec::new();
+    while moves.len() < length && game.turn != Turn::Scoring {
+        let stone = if game.turn == Turn::Black {Stone::Black} else {Stone::White};
+        let legal: Vec<usize> = (0..n * n).filter(|&x| game.valid_moves[x]).collect();
+        if legal.is_empty() {
+            game.pass(stone).unwrap();
+            moves.push((stone, None));
+        } else {
+            let pos = legal[rng.gen_range(0..legal.len())];
+            game.play(stone, pos).unwrap();
+            moves.push((stone, Some(pos)));
+        }
+    }
+    moves
+}
+
+fn legal_moves(c: &mut Criterion) {
+    let mut group = c.benchmark_group("legal_moves");
+    group.sample_size(10);
+    for (n, length) in [(9, 80), (13, 160), (19, 300)] {
+        let moves = record(n, length);
+        group.bench_with_input(BenchmarkId::new("game", n), &moves, |b, moves| b.iter(|| {
+            let mut game = Game::new(n, 7.5, 1, Placement::Free, Ruleset::Chinese).unwrap();
+            for &(stone, pos) in moves {
+                match pos {
+                    Some(pos) => game.play(stone, pos).unwrap(),
+                    None => game.pass(stone).unwrap()
+                }
+            }
+            game
+        }));
+        group.bench_with_input(BenchmarkId::new("naive", n), &moves, |b, moves| b.iter(|| {
+            let mut game = baseline::Game::new(n, 7.5, 1, baseline::Placement::Free, baseline::Ruleset::Chinese).unwrap();
+            for &(stone, pos) in moves {
+                let stone = if stone == Stone::Black {baseline::Stone::Black} else {baseline::Stone::White};
+                match pos {
+                    Some(pos) => game.play(stone, pos).unwrap(),
+                    None => game.pass(stone).unwrap()
+                }
+            }
+            game
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, legal_moves);
+criterion_main!(benches);
@@ -0,0 +1,200 @@
+use crate::{
+    model::{Game, Stone, Turn, Move},
+    config::EngineProfile,
+    gtp::{self, Client, Genmove, GtpError, Vertex},
+    pool::{Pool, PoolError},
+    engine
+};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    fmt,
+    error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+
+pub enum AnalysisError {
+    //Rated game in progress, or a player has not agreed to hints
+    Disabled,
+    //Running games only give hints to their players
+    Players,
+    RateLimited,
+    Engine(PoolError)
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalysisError::Disabled => write!(f, "Hints are disabled for this game"),
+            AnalysisError::Players => write!(f, "Hints are only given to players until the game ends"),
+            AnalysisError::RateLimited => write!(f, "Too many hint requests"),
+            AnalysisError::Engine(error) => write!(f, "Analysis failed: {}", error)
+        }
+    }
+}
+
+impl fmt::Debug for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for AnalysisError {}
+
+impl From<PoolError> for AnalysisError {
+    fn from(value: PoolError) -> Self {
+        AnalysisError::Engine(value)
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Analysis {
+    //Side the suggestion is for
+    pub stone: Stone,
+    //Vertex, "pass" or "resign"
+    #[serde(rename = "move")]
+    pub suggestion: String,
+    pub position: Option<usize>,
+    //Engine's score estimate, e.g. "W+3.5"
+    pub score: Option<String>,
+    //Per point from -1 (White) to 1 (Black), row by row from the top left
+    pub ownership: Option<Vec<f32>>
+}
+
+/*
+    Analysis engines, taken from the shared engine pool
+    At most `size` requests run at once
+    Each request replays the whole game on a cleared board
+*/
+pub struct Analyzer {
+    profile: EngineProfile,
+    size: usize,
+    permits: Semaphore,
+    pool: Arc<Pool>
+}
+
+//Side to move, or the player after the last move once the game is over
+fn next_stone(game: &Game) -> Stone {
+    match game.turn {
+        Turn::Handicap | Turn::Black => Stone::Black,
+        Turn::White => Stone::White,
+        Turn::Scoring | Turn::End => match game.moves.last() {
+            Some(Move {stone, ..}) => stone.opponent(),
+            None => Stone::Black
+        }
+    }
+}
+
+//GNU Go initial_influence territory values, positive for White
+fn parse_influence(text: &str, points: usize) -> Option<Vec<f32>> {
+    let values: Option<Vec<f32>> = text.split_whitespace()
+        .map(|value| value.parse::<f32>().ok().map(|value| -value.clamp(-1.0, 1.0)))
+        .collect();
+    values.filter(|values| values.len() == points)
+}
+
+impl Analyzer {
+    pub fn new(profile: EngineProfile, size: usize, pool: Arc<Pool>) -> Analyzer {
+        Analyzer {
+            profile,
+            size,
+            permits: Semaphore::new(size),
+            pool
+        }
+    }
+    pub async fn analyze(&self, game: &Game) -> Result<Analysis, PoolError> {
+        let _permit = self.permits.acquire().await.expect("Analyzer closed");
+        let mut engine = self.pool.checkout(&self.profile, game.ruleset).await?;
+        let analysis = Analyzer::run(&mut engine.client, game).await?;
+        //Engines that failed are dropped, which stops them
+        //At most one idle engine is kept for each request that can run at once
+        self.pool.checkin_at_most(engine, self.size);
+        Ok(analysis)
+    }
+    async fn run(client: &mut Client, game: &Game) -> Result<Analysis, GtpError> {
+        let commands: HashSet<String> = client.list_commands().await?.into_iter().collect();
+        let stone = next_stone(game);
+        engine::replay(client, game).await?;
+        //Estimates come before genmove, which plays the move
+        let score = if commands.contains("estimate_score") {
+            let text = client.command("estimate_score").await?;
+            text.split_whitespace().next().map(String::from)
+        } else {
+            None
+        };
+        let ownership = if commands.contains("initial_influence") {
+            let color: &str = stone.into();
+            let text = client.command(&format!("initial_influence {} territory_value", color)).await?;
+            parse_influence(&text, game.board.len())
+        } else {
+            None
+        };
+        let (suggestion, position) = match client.genmove(stone).await? {
+            Genmove::Move(Vertex::Point(position)) => {
+                let vertex = gtp::index_to_vertex(position, game.width, game.height).map_err(GtpError::Vertex)?;
+                (vertex, Some(position))
+            },
+            Genmove::Move(Vertex::Pass) => ("pass".to_string(), None),
+            Genmove::Resign => ("resign".to_string(), None)
+        };
+        Ok(Analysis {stone, suggestion, position, score, ownership})
+    }
+}
+
+//Minimum time between hints for each game
+pub struct RateLimiter {
+    interval: Duration,
+    last: Mutex<HashMap<usize, Instant>>
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> RateLimiter {
+        RateLimiter {
+            interval,
+            last: Mutex::new(HashMap::new())
+        }
+    }
+    //Record a request, returns false if the game asked too recently
+    pub fn check(&self, id: usize) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        last.retain(|_, time| now.duration_since(*time) < self.interval);
+        match last.entry(id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Placement, Ruleset};
+    #[test]
+    fn test_next_stone() {
+        let mut game = Game::new(9, 6.5, 1, Placement::Free, Ruleset::Chinese).unwrap();
+        assert_eq!(next_stone(&game), Stone::Black);
+        game.play(Stone::Black, 40).unwrap();
+        assert_eq!(next_stone(&game), Stone::White);
+        game.resign(Stone::White).unwrap();
+        assert_eq!(next_stone(&game), Stone::Black);
+    }
+    #[test]
+    fn test_parse_influence() {
+        let ownership = parse_influence(" 1.00 -0.50\n 0.00  2.00\n", 4).unwrap();
+        assert_eq!(ownership, [-1.0, 0.5, 0.0, -1.0]);
+        assert!(parse_influence("0.1 0.2 0.3", 4).is_none());
+    }
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(Duration::from_secs(60));
+        assert!(limiter.check(1));
+        assert!(!limiter.check(1));
+        assert!(limiter.check(2));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::model::Stone;
+use std::sync::OnceLock;
+
+/*
+    Board with incremental group tracking
+    Every stone belongs to a group, stored at the position of one of its stones
+    Groups keep their stones and liberties as bitsets, so capture and
+    suicide checks only look at the groups next to a move
+    The Zobrist hash of the position is kept up to date with every change
+*/
+
+//Largest supported board, 25x25
+const MAX_POINTS: usize = 625;
+const NONE: usize = usize::MAX;
+
+//Random keys for each point and color, the same for every board
+fn keys() -> &'static [[u64; 2]] {
+    static KEYS: OnceLock<Vec<[u64; 2]>> = OnceLock::new();
+    KEYS.get_or_init(|| {
+        //SplitMix64 with a fixed seed
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        (0..MAX_POINTS + 1).map(|_| [next(), next()]).collect()
+    })
+}
+
+pub fn zobrist(pos: usize, stone: Stone) -> u64 {
+    match stone {
+        Stone::Black => keys()[pos][0],
+        Stone::White => keys()[pos][1],
+        Stone::Empty => 0
+    }
+}
+
+//Key for the player who made the last move, for situational superko
+pub fn mover(stone: Stone) -> u64 {
+    zobrist(MAX_POINTS, stone)
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Bits(Vec<u64>);
+
+impl Bits {
+    fn new(points: usize) -> Bits {
+        Bits(vec![0; points.div_ceil(64)])
+    }
+    fn insert(&mut self, pos: usize) {
+        self.0[pos / 64] |= 1 << (pos % 64);
+    }
+    fn remove(&mut self, pos: usize) {
+        self.0[pos / 64] &= !(1 << (pos % 64));
+    }
+    fn count(&self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+    fn union(&mut self, other: &Bits) {
+        for (word, other) in self.0.iter_mut().zip(&other.0) {
+            *word |= other;
+        }
+    }
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(index, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(index * 64 + bit)
+            })
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Group {
+    stones: Bits,
+    liberties: Bits,
+    //Zobrist hash of the group's stones
+    hash: u64
+}
+
+//Result of a move, worked out without playing it
+pub struct Outcome {
+    pub hash: u64,
+    //Stones would be removed, the player's own for suicide
+    pub captures: bool,
+    pub suicide: bool
+}
+
+#[derive(Clone, Debug)]
+pub struct Board {
+    width: usize,
+    stones: Vec<Stone>,
+    //Position of each stone's group, NONE for empty points
+    roots: Vec<usize>,
+    groups: Vec<Option<Group>>,
+    hash: u64
+}
+
+impl Board {
+    pub fn new(width: usize, height: usize) -> Board {
+        let points = width * height;
+        assert!(points <= MAX_POINTS);
+        Board {
+            width,
+            stones: vec![Stone::Empty; points],
+            roots: vec![NONE; points],
+            groups: vec![None; points],
+            hash: 0
+        }
+    }
+    //Board with the given stones, every group must have a liberty
+    pub fn from_stones(width: usize, height: usize, stones: &[Stone]) -> Board {
+        let mut board = Board::new(width, height);
+        for (pos, &stone) in stones.iter().enumerate() {
+            if stone != Stone::Empty {
+                board.place(stone, pos);
+            }
+        }
+        board
+    }
+    pub fn stones(&self) -> &[Stone] {
+        &self.stones
+    }
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+    fn neighbors(&self, pos: usize) -> impl Iterator<Item = usize> {
+        let n = self.width;
+        let (x, y) = (pos % n, pos / n);
+        [
+            (x > 0).then(|| pos - 1),
+            (x + 1 < n).then(|| pos + 1),
+            (y > 0).then(|| pos - n),
+            (pos + n < self.stones.len()).then(|| pos + n)
+        ].into_iter().flatten()
+    }
+    fn group(&self, pos: usize) -> &Group {
+        self.groups[self.roots[pos]].as_ref().expect("Stone without a group")
+    }
+    pub fn check(&self, stone: Stone, pos: usize) -> Outcome {
+        let mut hash = self.hash ^ zobrist(pos, stone);
+        let mut captured = [NONE; 4];
+        let mut friends = [NONE; 4];
+        //Whether the new stone's group keeps a liberty
+        let mut alive = false;
+        for (index, neighbor) in self.neighbors(pos).enumerate() {
+            let color = self.stones[neighbor];
+            if color == Stone::Empty {
+                alive = true;
+                continue
+            }
+            let root = self.roots[neighbor];
+            let group = self.group(neighbor);
+            if color == stone {
+                alive |= group.liberties.count() > 1;
+                friends[index] = root;
+            } else if group.liberties.count() == 1 && !captured.contains(&root) {
+                captured[index] = root;
+                hash ^= group.hash;
+            }
+        }
+        let captures = captured.iter().any(|&root| root != NONE);
+        if alive || captures {
+            return Outcome {hash, captures, suicide: false}
+        }
+        //The stone and its group are removed again
+        let mut hash = self.hash;
+        for (index, &root) in friends.iter().enumerate() {
+            if root != NONE && !friends[..index].contains(&root) {
+                hash ^= self.groups[root].as_ref().unwrap().hash;
+            }
+        }
+        Outcome {hash, captures: true, suicide: true}
+    }
+    //Play a stone and return the captured stones, including the player's own for suicide
+    pub fn place(&mut self, stone: Stone, pos: usize) -> Vec<usize> {
+        debug_assert!(self.stones[pos] == Stone::Empty);
+        self.stones[pos] = stone;
+        self.hash ^= zobrist(pos, stone);
+        let mut group = Group {
+            stones: Bits::new(self.stones.len()),
+            liberties: Bits::new(self.stones.len()),
+            hash: zobrist(pos, stone)
+        };
+        group.stones.insert(pos);
+        let neighbors: Vec<usize> = self.neighbors(pos).collect();
+        let mut opponents = Vec::new();
+        for &neighbor in &neighbors {
+            let root = self.roots[neighbor];
+            if self.stones[neighbor] == Stone::Empty {
+                group.liberties.insert(neighbor);
+            } else if self.stones[neighbor] == stone {
+                //Merge into the new group
+                if let Some(friend) = self.groups[root].take() {
+                    for stone in friend.stones.iter() {
+                        self.roots[stone] = pos;
+                    }
+                    group.stones.union(&friend.stones);
+                    group.liberties.union(&friend.liberties);
+                    group.hash ^= friend.hash;
+                }
+            } else if !opponents.contains(&root) {
+                self.groups[root].as_mut().unwrap().liberties.remove(pos);
+                opponents.push(root);
+            }
+        }
+        group.liberties.remove(pos);
+        self.roots[pos] = pos;
+        self.groups[pos] = Some(group);
+        //Capture
+        let mut captures = Vec::new();
+        for root in opponents {
+            if self.groups[root].as_ref().unwrap().liberties.count() == 0 {
+                captures.extend(self.remove(root));
+            }
+        }
+        //Self-capture
+        if captures.is_empty() && self.groups[pos].as_ref().unwrap().liberties.count() == 0 {
+            captures.extend(self.remove(pos));
+        }
+        captures
+    }
+    //Take a group off the board, its neighbors gain liberties
+    fn remove(&mut self, root: usize) -> Vec<usize> {
+        let group = self.groups[root].take().unwrap();
+        self.hash ^= group.hash;
+        let stones: Vec<usize> = group.stones.iter().collect();
+        for &pos in &stones {
+            self.stones[pos] = Stone::Empty;
+            self.roots[pos] = NONE;
+        }
+        for &pos in &stones {
+            for neighbor in self.neighbors(pos).collect::<Vec<usize>>() {
+                let root = self.roots[neighbor];
+                if root != NONE {
+                    self.groups[root].as_mut().unwrap().liberties.insert(pos);
+                }
+            }
+        }
+        stones
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_bits() {
+        let mut bits = Bits::new(130);
+        for pos in [0, 63, 64, 129] {
+            bits.insert(pos);
+        }
+        bits.remove(63);
+        assert_eq!(bits.count(), 3);
+        assert_eq!(bits.iter().collect::<Vec<usize>>(), [0, 64, 129]);
+    }
+    #[test]
+    fn test_capture() {
+        let mut board = Board::new(5, 5);
+        board.place(Stone::Black, 1);
+        board.place(Stone::White, 0);
+        let outcome = board.check(Stone::Black, 5);
+        assert!(outcome.captures && !outcome.suicide);
+        assert_eq!(board.place(Stone::Black, 5), vec![0]);
+        assert_eq!(outcome.hash, board.hash());
+        //Same hash as the position built without the captured stone
+        let mut expected = Board::new(5, 5);
+        expected.place(Stone::Black, 5);
+        expected.place(Stone::Black, 1);
+        assert_eq!(board.hash(), expected.hash());
+        assert_eq!(board.group(1).liberties.count(), 3);
+        //A stone without liberties that captures is not suicide
+        board.place(Stone::White, 2);
+        board.place(Stone::White, 6);
+        let outcome = board.check(Stone::White, 0);
+        assert!(outcome.captures && !outcome.suicide);
+    }
+    #[test]
+    fn test_suicide() {
+        let mut board = Board::new(5, 5);
+        for pos in [2, 6, 10] {
+            board.place(Stone::Black, pos);
+        }
+        board.place(Stone::White, 0);
+        board.place(Stone::White, 5);
+        let empty = Board::new(5, 5).hash();
+        let outcome = board.check(Stone::White, 1);
+        assert!(outcome.suicide);
+        //White 1 removes the three White stones
+        assert_eq!(board.place(Stone::White, 1).len(), 3);
+        let mut expected = Board::new(5, 5);
+        for pos in [2, 6, 10] {
+            expected.place(Stone::Black, pos);
+        }
+        assert_eq!(outcome.hash, expected.hash());
+        assert_eq!(board.hash(), expected.hash());
+        assert_ne!(board.hash(), empty);
+        //Liberties are given back to the surrounding stones
+        assert_eq!(board.group(2).liberties.count(), 3);
+    }
+}
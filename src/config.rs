@@ -0,0 +1,468 @@
+use crate::model::MAX_BOARD_SIZE;
+use clap::Parser;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    error,
+    fs,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration
+};
+
+/*
+    Configuration sources, highest priority first:
+    1. Command line flags
+    2. Environment variables (CASUAL_GO_*)
+    3. TOML file given by --config
+    4. Defaults
+*/
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    //Profile used by --engine overrides and old "bot" form values
+    pub default_engine: String,
+    //Profile asked for dead stones and a suggested result after two passes
+    pub scoring_engine: Option<String>,
+    //Profile answering hint requests, hints are off if unset
+    pub analysis_engine: Option<String>,
+    pub server: ServerConfig,
+    pub engines: BTreeMap<String, EngineProfile>,
+    pub limits: Limits
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    pub database: PathBuf,
+    //Serve /static from this directory, if set
+    pub static_dir: Option<PathBuf>,
+    //Seconds to wait for clients after a shutdown notice
+    pub drain: u64,
+    //Separate listener for /metrics, e.g. on localhost, none if unset
+    pub metrics_address: Option<SocketAddr>
+}
+
+//GTP engine offered as an opponent
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EngineProfile {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    //Empty for any size
+    #[serde(default)]
+    pub board_sizes: Vec<u32>,
+    #[serde(default)]
+    pub strength: String,
+    //Pass GNU Go rule flags (--japanese-rules etc.)
+    #[serde(default)]
+    pub gnugo_rules: bool,
+    //Seconds to answer any command, genmove included
+    #[serde(default = "EngineProfile::default_timeout")]
+    pub timeout: u64
+}
+
+impl EngineProfile {
+    fn default_timeout() -> u64 {
+        30
+    }
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+    pub fn supports(&self, board_size: u32) -> bool {
+        self.board_sizes.is_empty() || self.board_sizes.contains(&board_size)
+    }
+    //Display name with strength
+    pub fn label(&self) -> String {
+        if self.strength.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, self.strength)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    //Seconds to wait for players before a game is aborted
+    pub lobby_timeout: u64,
+    pub max_fixed_time: u32,
+    //Per-move overtime: Fischer increment, byo-yomi period or Canadian time per stone
+    pub max_added_time: u32,
+    pub min_board_size: u32,
+    pub max_board_size: u32,
+    //Longest pause before bot moves, in seconds
+    pub max_move_delay: u64,
+    //Moves before an engine-vs-engine game is aborted
+    pub max_bot_moves: usize,
+    //Seconds between hints in each game
+    pub hint_interval: u64,
+    //Analysis engines running at once
+    pub analysis_engines: usize,
+    //Engine processes running or idle at once, across all games
+    pub max_engines: usize,
+    //Seconds an engine start waits for a free slot before it fails
+    pub engine_wait: u64
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: 80,
+            database: "games.db".into(),
+            static_dir: None,
+            drain: 10,
+            metrics_address: None
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let gnugo = EngineProfile {
+            name: "GNU Go level 1".into(),
+            command: "gnugo".into(),
+            args: vec!["--mode".into(), "gtp".into(), "--level".into(), "1".into()],
+            //GNU Go plays up to 19x19
+            board_sizes: (2..=19).collect(),
+            strength: String::new(),
+            gnugo_rules: true,
+            timeout: EngineProfile::default_timeout()
+        };
+        Config {
+            default_engine: "gnugo".into(),
+            scoring_engine: None,
+            analysis_engine: None,
+            server: ServerConfig::default(),
+            engines: BTreeMap::from([("gnugo".to_string(), gnugo)]),
+            limits: Limits::default()
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            lobby_timeout: 5 * 60,
+            max_fixed_time: 3600,
+            max_added_time: 60,
+            min_board_size: 2,
+            max_board_size: MAX_BOARD_SIZE as u32,
+            max_move_delay: 10,
+            max_bot_moves: 1000,
+            hint_interval: 10,
+            analysis_engines: 2,
+            max_engines: 16,
+            engine_wait: 5
+        }
+    }
+}
+
+impl Limits {
+    pub fn lobby_timeout(&self) -> Duration {
+        Duration::from_secs(self.lobby_timeout)
+    }
+    pub fn hint_interval(&self) -> Duration {
+        Duration::from_secs(self.hint_interval)
+    }
+    pub fn engine_wait(&self) -> Duration {
+        Duration::from_secs(self.engine_wait)
+    }
+}
+
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "Cannot read config file {}: {}", path.display(), error),
+            ConfigError::Parse(path, error) => write!(f, "Invalid config file {}: {}", path.display(), error),
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message)
+        }
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for ConfigError {}
+
+#[derive(Parser, Debug, Default)]
+#[command(about = "Casual Go server")]
+pub struct Args {
+    #[arg(short, long, env = "CASUAL_GO_CONFIG", help = "TOML configuration file")]
+    pub config: Option<PathBuf>,
+    #[arg(long, env = "CASUAL_GO_ADDRESS", help = "Bind address")]
+    pub address: Option<IpAddr>,
+    #[arg(short, long, env = "CASUAL_GO_PORT", help = "Bind port")]
+    pub port: Option<u16>,
+    #[arg(long, env = "CASUAL_GO_DATABASE", help = "SQLite database path")]
+    pub database: Option<PathBuf>,
+    #[arg(long, env = "CASUAL_GO_STATIC_DIR", help = "Serve /static from this directory")]
+    pub static_dir: Option<PathBuf>,
+    #[arg(long, env = "CASUAL_GO_DRAIN", help = "Seconds to wait for clients on shutdown")]
+    pub drain: Option<u64>,
+    #[arg(long, env = "CASUAL_GO_METRICS_ADDRESS", help = "Address and port serving /metrics, kept off the public listener")]
+    pub metrics_address: Option<SocketAddr>,
+    #[arg(long, env = "CASUAL_GO_ENGINE", help = "GTP engine command of the default profile")]
+    pub engine: Option<String>,
+    #[arg(
+        long = "engine-arg",
+        env = "CASUAL_GO_ENGINE_ARGS",
+        value_delimiter = ' ',
+        allow_hyphen_values = true,
+        help = "GTP engine argument of the default profile, replaces the configured arguments"
+    )]
+    pub engine_args: Option<Vec<String>>,
+    #[arg(long, env = "CASUAL_GO_SCORING_ENGINE", help = "Engine profile that suggests dead stones and a result")]
+    pub scoring_engine: Option<String>,
+    #[arg(long, env = "CASUAL_GO_ANALYSIS_ENGINE", help = "Engine profile that answers hint requests")]
+    pub analysis_engine: Option<String>,
+    #[arg(long, env = "CASUAL_GO_LOBBY_TIMEOUT", help = "Seconds to wait for players")]
+    pub lobby_timeout: Option<u64>,
+    #[arg(long, env = "CASUAL_GO_MAX_FIXED_TIME", help = "Maximum main time in seconds")]
+    pub max_fixed_time: Option<u32>,
+    #[arg(long, env = "CASUAL_GO_MAX_ADDED_TIME", help = "Maximum overtime per move in seconds")]
+    pub max_added_time: Option<u32>,
+    #[arg(long, env = "CASUAL_GO_MIN_BOARD_SIZE", help = "Smallest board size")]
+    pub min_board_size: Option<u32>,
+    #[arg(long, env = "CASUAL_GO_MAX_BOARD_SIZE", help = "Largest board size")]
+    pub max_board_size: Option<u32>,
+    #[arg(long, env = "CASUAL_GO_MAX_MOVE_DELAY", help = "Longest pause before bot moves in seconds")]
+    pub max_move_delay: Option<u64>,
+    #[arg(long, env = "CASUAL_GO_MAX_BOT_MOVES", help = "Moves before engine-vs-engine games are aborted")]
+    pub max_bot_moves: Option<usize>,
+    #[arg(long, env = "CASUAL_GO_MAX_ENGINES", help = "Maximum engine processes across all games")]
+    pub max_engines: Option<usize>
+}
+
+impl Config {
+    pub fn parse(text: &str, path: PathBuf) -> Result<Config, ConfigError> {
+        toml::from_str(text).map_err(|error| ConfigError::Parse(path, error))
+    }
+    //Read the config file named in the arguments and apply overrides
+    pub fn load(args: Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|error| ConfigError::Io(path.clone(), error))?;
+                Config::parse(&text, path.clone())?
+            },
+            None => Config::default()
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+    fn apply(&mut self, args: Args) {
+        let server = &mut self.server;
+        if let Some(address) = args.address {
+            server.address = address;
+        }
+        if let Some(port) = args.port {
+            server.port = port;
+        }
+        if let Some(database) = args.database {
+            server.database = database;
+        }
+        if let Some(static_dir) = args.static_dir {
+            server.static_dir = Some(static_dir);
+        }
+        if let Some(drain) = args.drain {
+            server.drain = drain;
+        }
+        if let Some(metrics_address) = args.metrics_address {
+            server.metrics_address = Some(metrics_address);
+        }
+        if let Some(profile) = self.engines.get_mut(&self.default_engine) {
+            if let Some(command) = args.engine {
+                profile.command = command;
+            }
+            if let Some(engine_args) = args.engine_args {
+                profile.args = engine_args;
+            }
+        }
+        if let Some(scoring_engine) = args.scoring_engine {
+            self.scoring_engine = Some(scoring_engine);
+        }
+        if let Some(analysis_engine) = args.analysis_engine {
+            self.analysis_engine = Some(analysis_engine);
+        }
+        let limits = &mut self.limits;
+        if let Some(lobby_timeout) = args.lobby_timeout {
+            limits.lobby_timeout = lobby_timeout;
+        }
+        if let Some(max_fixed_time) = args.max_fixed_time {
+            limits.max_fixed_time = max_fixed_time;
+        }
+        if let Some(max_added_time) = args.max_added_time {
+            limits.max_added_time = max_added_time;
+        }
+        if let Some(min_board_size) = args.min_board_size {
+            limits.min_board_size = min_board_size;
+        }
+        if let Some(max_board_size) = args.max_board_size {
+            limits.max_board_size = max_board_size;
+        }
+        if let Some(max_move_delay) = args.max_move_delay {
+            limits.max_move_delay = max_move_delay;
+        }
+        if let Some(max_bot_moves) = args.max_bot_moves {
+            limits.max_bot_moves = max_bot_moves;
+        }
+        if let Some(max_engines) = args.max_engines {
+            limits.max_engines = max_engines;
+        }
+    }
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+        let limits = &self.limits;
+        if !self.engines.contains_key(&self.default_engine) {
+            return Err(ConfigError::Invalid(format!("default_engine {} is not a configured engine", self.default_engine)))
+        }
+        if let Some(id) = self.scoring_engine.as_ref().filter(|id| !self.engines.contains_key(*id)) {
+            return Err(ConfigError::Invalid(format!("scoring_engine {} is not a configured engine", id)))
+        }
+        if let Some(id) = self.analysis_engine.as_ref().filter(|id| !self.engines.contains_key(*id)) {
+            return Err(ConfigError::Invalid(format!("analysis_engine {} is not a configured engine", id)))
+        }
+        if self.limits.analysis_engines == 0 {
+            return invalid("limits.analysis_engines must be at least 1")
+        }
+        if self.limits.max_engines == 0 {
+            return invalid("limits.max_engines must be at least 1")
+        }
+        for (id, profile) in &self.engines {
+            if id == "human" || id == "bot" {
+                return Err(ConfigError::Invalid(format!("engine id {} is reserved", id)))
+            }
+            if profile.command.trim().is_empty() {
+                return Err(ConfigError::Invalid(format!("engines.{}.command must not be empty", id)))
+            }
+            if profile.timeout == 0 {
+                return Err(ConfigError::Invalid(format!("engines.{}.timeout must be at least 1 second", id)))
+            }
+        }
+        if limits.lobby_timeout == 0 {
+            return invalid("limits.lobby_timeout must be at least 1 second")
+        }
+        if limits.max_fixed_time == 0 {
+            return invalid("limits.max_fixed_time must be at least 1 second")
+        }
+        if limits.min_board_size < 2 || limits.max_board_size > MAX_BOARD_SIZE as u32 {
+            return invalid("board size limits must be within 2 to 25")
+        }
+        if limits.min_board_size > limits.max_board_size {
+            return invalid("limits.min_board_size is larger than limits.max_board_size")
+        }
+        if let Some(dir) = &self.server.static_dir {
+            if !dir.is_dir() {
+                return Err(ConfigError::Invalid(format!("static_dir {} is not a directory", dir.display())))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("
+            default_engine = \"pachi\"
+            [server]
+            port = 8080
+            metrics_address = \"127.0.0.1:9100\"
+            [engines.pachi]
+            name = \"Pachi\"
+            command = \"pachi\"
+            board_sizes = [9, 13]
+            strength = \"3d\"
+            [limits]
+            max_board_size = 13
+        ", "test.toml".into()).unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.metrics_address, Some(SocketAddr::from(([127, 0, 0, 1], 9100))));
+        let pachi = &config.engines["pachi"];
+        assert_eq!(pachi.command, "pachi");
+        assert!(pachi.args.is_empty());
+        assert!(pachi.supports(13) && !pachi.supports(19));
+        assert_eq!(pachi.label(), "Pachi (3d)");
+        assert_eq!(pachi.timeout, 30);
+        assert!(!config.engines.contains_key("gnugo"));
+        assert_eq!(config.limits.max_board_size, 13);
+        //Defaults
+        assert_eq!(config.limits.lobby_timeout, 300);
+        assert_eq!(config.limits.min_board_size, 2);
+        let gnugo = &Config::default().engines["gnugo"];
+        assert!(gnugo.supports(19) && !gnugo.supports(25));
+        assert!(config.validate().is_ok());
+    }
+    #[test]
+    fn test_example() {
+        let config = Config::parse(include_str!("../casual-go.toml"), "casual-go.toml".into()).unwrap();
+        assert!(config.validate().is_ok());
+        //Same sizes as the built-in GNU Go profile
+        for profile in config.engines.values().filter(|profile| profile.gnugo_rules) {
+            assert_eq!(profile.board_sizes, Config::default().engines["gnugo"].board_sizes);
+        }
+    }
+    #[test]
+    fn test_errors() {
+        let error = Config::parse("[server]\nprot = 80", "test.toml".into()).unwrap_err();
+        assert!(error.to_string().contains("prot"));
+        let mut config = Config::default();
+        config.limits.min_board_size = 15;
+        config.limits.max_board_size = 9;
+        assert!(config.validate().is_err());
+        let mut config = Config::default();
+        config.limits.max_board_size = 26;
+        assert!(config.validate().is_err());
+        config.limits.max_board_size = 25;
+        config.limits.min_board_size = 1;
+        assert!(config.validate().is_err());
+        let mut config = Config::default();
+        config.limits.max_engines = 0;
+        assert!(config.validate().is_err());
+        let config = Config {
+            default_engine: "katago".into(),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+        let config = Config {
+            scoring_engine: Some("katago".into()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+    #[test]
+    fn test_overrides() {
+        let mut config = Config::default();
+        config.apply(Args {
+            port: Some(3000),
+            max_engines: Some(4),
+            engine_args: Some(vec!["--mode".into(), "gtp".into()]),
+            ..Args::default()
+        });
+        assert_eq!(config.server.port, 3000);
+        assert_eq!(config.limits.max_engines, 4);
+        assert_eq!(config.engines["gnugo"].args, ["--mode", "gtp"]);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::model::MAX_BOARD_SIZE;
+use std::{error, fmt};
+
+/*
+    Board coordinates
+    A Coord is a point by column and row from the top left, always on the board it
+    was made for, which it keeps so every text form can be written without checks
+    Text forms:
+    GTP: column letter without I, row counted from the bottom ("D4")
+    SGF: column and row letters from the top left ("dd")
+    Human: column and row numbers from the bottom left ("4-4")
+*/
+
+//GTP column letters, enough for the largest board
+const COLUMNS: &[u8; MAX_BOARD_SIZE] = b"ABCDEFGHJKLMNOPQRSTUVWXYZ";
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum CoordError {
+    //Not a coordinate in any form
+    Malformed(String),
+    //A coordinate, but not on this board
+    OffBoard(String),
+    Size(usize, usize)
+}
+
+impl fmt::Display for CoordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoordError::Malformed(text) => write!(f, "Malformed coordinate {:?}", text),
+            CoordError::OffBoard(text) => write!(f, "Coordinate {} is off the board", text),
+            CoordError::Size(width, height) => write!(f, "Unsupported board size {}x{}", width, height)
+        }
+    }
+}
+
+impl fmt::Debug for CoordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for CoordError {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Coord {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize
+}
+
+impl Coord {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Result<Coord, CoordError> {
+        size(width, height)?;
+        if x >= width || y >= height {
+            return Err(CoordError::OffBoard(format!("({}, {})", x, y)))
+        }
+        Ok(Coord {x, y, width, height})
+    }
+    //Board index, row by row from the top left
+    pub fn from_index(index: usize, width: usize, height: usize) -> Result<Coord, CoordError> {
+        size(width, height)?;
+        if index >= width * height {
+            return Err(CoordError::OffBoard(index.to_string()))
+        }
+        Ok(Coord {x: index % width, y: index / width, width, height})
+    }
+    pub fn x(self) -> usize {
+        self.x
+    }
+    pub fn y(self) -> usize {
+        self.y
+    }
+    pub fn index(self) -> usize {
+        self.width * self.y + self.x
+    }
+    pub fn gtp(self) -> String {
+        format!("{}{}", COLUMNS[self.x] as char, self.height - self.y)
+    }
+    pub fn sgf(self) -> String {
+        [(b'a' + self.x as u8) as char, (b'a' + self.y as u8) as char].iter().collect()
+    }
+    pub fn human(self) -> String {
+        format!("{}-{}", self.x + 1, self.height - self.y)
+    }
+    //Letters are case insensitive
+    pub fn parse_gtp(text: &str, width: usize, height: usize) -> Result<Coord, CoordError> {
+        size(width, height)?;
+        let malformed = || CoordError::Malformed(text.to_string());
+        let mut chars = text.chars();
+        let column = chars.next().ok_or_else(malformed)?.to_ascii_uppercase();
+        let x = COLUMNS.iter()
+            .position(|&letter| letter as char == column)
+            .ok_or_else(malformed)?;
+        let row = number(chars.as_str()).ok_or_else(malformed)?;
+        //Rows count from 1 at the bottom
+        if x >= width || row == 0 || row > height {
+            return Err(CoordError::OffBoard(text.to_string()))
+        }
+        Ok(Coord {x, y: height - row, width, height})
+    }
+    pub fn parse_sgf(text: &str, width: usize, height: usize) -> Result<Coord, CoordError> {
+        size(width, height)?;
+        match *text.as_bytes() {
+            [x, y] if x.is_ascii_lowercase() && y.is_ascii_lowercase() => {
+                let (x, y) = ((x - b'a') as usize, (y - b'a') as usize);
+                if x >= width || y >= height {
+                    return Err(CoordError::OffBoard(text.to_string()))
+                }
+                Ok(Coord {x, y, width, height})
+            },
+            _ => Err(CoordError::Malformed(text.to_string()))
+        }
+    }
+    pub fn parse_human(text: &str, width: usize, height: usize) -> Result<Coord, CoordError> {
+        size(width, height)?;
+        let malformed = || CoordError::Malformed(text.to_string());
+        let (column, row) = text.split_once('-').ok_or_else(malformed)?;
+        let column = number(column).ok_or_else(malformed)?;
+        let row = number(row).ok_or_else(malformed)?;
+        if column == 0 || column > width || row == 0 || row > height {
+            return Err(CoordError::OffBoard(text.to_string()))
+        }
+        Ok(Coord {x: column - 1, y: height - row, width, height})
+    }
+    //Any of the three forms, as typed by a player
+    pub fn parse(text: &str, width: usize, height: usize) -> Result<Coord, CoordError> {
+        let text = text.trim();
+        if text.contains('-') {
+            Coord::parse_human(text, width, height)
+        } else if text.len() == 2 && text.bytes().all(|byte| byte.is_ascii_lowercase()) {
+            Coord::parse_sgf(text, width, height)
+        } else {
+            Coord::parse_gtp(text, width, height)
+        }
+    }
+}
+
+//Plain decimal digits, without signs
+fn number(text: &str) -> Option<usize> {
+    if text.is_empty() || text.len() > 3 || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None
+    }
+    text.parse().ok()
+}
+
+fn size(width: usize, height: usize) -> Result<(), CoordError> {
+    let range = 1..=MAX_BOARD_SIZE;
+    if range.contains(&width) && range.contains(&height) {
+        Ok(())
+    } else {
+        Err(CoordError::Size(width, height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    #[test]
+    fn test_formats() {
+        let coord = Coord::from_index(288, 19, 19).unwrap();
+        assert_eq!((coord.x(), coord.y()), (3, 15));
+        assert_eq!(coord.gtp(), "D4");
+        assert_eq!(coord.sgf(), "dp");
+        assert_eq!(coord.human(), "4-4");
+        //No I column
+        assert_eq!(Coord::from_index(8, 19, 19).unwrap().gtp(), "J19");
+        assert_eq!(Coord::from_index(624, 25, 25).unwrap().gtp(), "Z1");
+        assert_eq!(Coord::from_index(624, 25, 25).unwrap().sgf(), "yy");
+        //Rectangular boards count rows from their own bottom
+        assert_eq!(Coord::from_index(2, 3, 5).unwrap().gtp(), "C5");
+        assert_eq!(Coord::from_index(12, 3, 5).unwrap().human(), "1-1");
+    }
+    #[test]
+    fn test_parse() {
+        for text in ["D4", "d4", "dp", "4-4", " D4 "] {
+            assert_eq!(Coord::parse(text, 19, 19).unwrap().index(), 288, "{}", text);
+        }
+        assert_eq!(Coord::parse_gtp("T19", 19, 19).unwrap().index(), 18);
+        assert_eq!(Coord::parse_gtp("C5", 3, 5).unwrap().index(), 2);
+        let malformed = |text: &str| CoordError::Malformed(text.to_string());
+        let off = |text: &str| CoordError::OffBoard(text.to_string());
+        assert_eq!(Coord::parse_gtp("", 19, 19), Err(malformed("")));
+        assert_eq!(Coord::parse_gtp("I5", 19, 19), Err(malformed("I5")));
+        assert_eq!(Coord::parse_gtp("5", 19, 19), Err(malformed("5")));
+        assert_eq!(Coord::parse_gtp("K+5", 19, 19), Err(malformed("K+5")));
+        assert_eq!(Coord::parse_gtp("ÄB", 19, 19), Err(malformed("ÄB")));
+        assert_eq!(Coord::parse_gtp("K0", 19, 19), Err(off("K0")));
+        assert_eq!(Coord::parse_gtp("K10", 9, 9), Err(off("K10")));
+        assert_eq!(Coord::parse_gtp("A20", 19, 19), Err(off("A20")));
+        assert_eq!(Coord::parse_gtp("D1", 3, 5), Err(off("D1")));
+        assert_eq!(Coord::parse_sgf("tt", 19, 19), Err(off("tt")));
+        assert_eq!(Coord::parse_sgf("dD", 19, 19), Err(malformed("dD")));
+        assert_eq!(Coord::parse_human("0-4", 19, 19), Err(off("0-4")));
+        assert_eq!(Coord::parse_human("4-20", 19, 19), Err(off("4-20")));
+        assert_eq!(Coord::parse_human("4-", 19, 19), Err(malformed("4-")));
+        assert_eq!(Coord::from_index(361, 19, 19), Err(off("361")));
+        assert_eq!(Coord::from_index(0, 26, 26), Err(CoordError::Size(26, 26)));
+        assert_eq!(Coord::from_index(0, 0, 0), Err(CoordError::Size(0, 0)));
+    }
+    fn board() -> impl Strategy<Value = (usize, usize, usize)> {
+        (2..=MAX_BOARD_SIZE, 2..=MAX_BOARD_SIZE)
+            .prop_flat_map(|(width, height)| (Just(width), Just(height), 0..width * height))
+    }
+    proptest! {
+        #[test]
+        fn round_trips((width, height, index) in board()) {
+            let coord = Coord::from_index(index, width, height).unwrap();
+            prop_assert_eq!(coord.index(), index);
+            prop_assert_eq!(Coord::parse_gtp(&coord.gtp(), width, height), Ok(coord));
+            prop_assert_eq!(Coord::parse_sgf(&coord.sgf(), width, height), Ok(coord));
+            prop_assert_eq!(Coord::parse_human(&coord.human(), width, height), Ok(coord));
+            for text in [coord.gtp(), coord.sgf(), coord.human()] {
+                prop_assert_eq!(Coord::parse(&text, width, height), Ok(coord));
+            }
+        }
+        #[test]
+        fn off_board_points((width, height, index) in board()) {
+            let coord = Coord::from_index(index, width, height).unwrap();
+            let off = |result: Result<Coord, CoordError>| matches!(result, Err(CoordError::OffBoard(_)));
+            //The same text on a board without the point's column or row
+            if coord.x() > 0 {
+                prop_assert!(off(Coord::parse_gtp(&coord.gtp(), coord.x(), height)));
+                prop_assert!(off(Coord::parse_sgf(&coord.sgf(), coord.x(), height)));
+                prop_assert!(off(Coord::parse_human(&coord.human(), coord.x(), height)));
+            }
+            if coord.y() > 0 {
+                prop_assert!(off(Coord::parse_sgf(&coord.sgf(), width, coord.y())));
+            }
+            if height - coord.y() > 1 {
+                let row = height - coord.y();
+                prop_assert!(off(Coord::parse_gtp(&coord.gtp(), width, row - 1)));
+                prop_assert!(off(Coord::parse_human(&coord.human(), width, row - 1)));
+            }
+            prop_assert!(off(Coord::from_index(width * height + index, width, height)));
+        }
+        #[test]
+        fn never_panics(text in "\\PC{0,6}", width in 0..30usize, height in 0..30usize) {
+            let _ = Coord::parse(&text, width, height);
+            let _ = Coord::parse_gtp(&text, width, height);
+            let _ = Coord::parse_sgf(&text, width, height);
+            let _ = Coord::parse_human(&text, width, height);
+        }
+    }
+}
@@ -1,175 +1,413 @@
 use crate::{
-    model::Stone,
+    model::{Action, Game, GameResult, Placement, Stone, Ruleset, Ko, Scoring},
+    timer::TimeControl,
+    config::EngineProfile,
+    gtp::{Client, Genmove, GtpError, Status, Vertex},
+    pool::{Pool, PoolError, Slot},
     session
 };
-use tokio::{
-    io::{BufReader, AsyncBufReadExt, AsyncWriteExt},
-    process::Command,
-    sync::mpsc
-};
-use std::process::Stdio;
+use tokio::sync::mpsc;
+use std::{collections::VecDeque, sync::Arc};
 
 pub enum Message {
-    Play(Stone, u32),
+    Play(Stone, Vertex),
     Genmove,
     Handicap(u32),
+    //Handicap stones already on the game's star points
+    FixedHandicap(Vec<usize>),
+    TimeSettings(TimeControl),
+    TimeLeft(u32, u32),
+    //Take back moves until the engine has seen this many
+    Undo(usize),
     Quit
 }
 
-fn index_to_vertex(index: u32, board_size: u32) -> String {
-    let x = index % board_size;
-    let y = index / board_size;
-    let col = unsafe {
-        char::from_u32_unchecked(
-            if x <= 7 {
-                'A' as u32 + x
-            } else {
-                'A' as u32 + x + 1
-            }
-        )
-    };
-    let row = board_size - y;
-    col.to_string() + &row.to_string()
+//GTP has no Fischer increment, so the engine is only told about main time
+fn time_settings(time_control: TimeControl) -> String {
+    match time_control {
+        TimeControl::Absolute{main} | TimeControl::Fischer{main, ..} => {
+            format!("time_settings {} 0 0", main)
+        },
+        TimeControl::Byoyomi{main, periods, period} => {
+            format!("kgs-time_settings byoyomi {} {} {}", main, period, periods)
+        },
+        TimeControl::Canadian{main, stones, period} => {
+            format!("time_settings {} {} {}", main, period, stones)
+        }
+    }
 }
 
-fn vertex_to_index(vertex: &str, board_size: u32) -> Option<u32> {
-    let c: char = vertex.chars().next().unwrap();
-    let x = if c < 'I' {
-        c as u32 - 'A' as u32
-    } else {
-        c as u32 - 'A' as u32 - 1
-    };
-    if let Ok(row) = vertex[1..].parse::<u32>() {
-        let y = board_size - row;
-        Some(board_size * y + x)
-    } else {
-        None
+//GNU Go rule options
+fn ruleset_args(ruleset: Ruleset) -> Vec<&'static str> {
+    let mut args = vec![
+        if ruleset.scoring() == Scoring::Territory {
+            "--japanese-rules"
+        } else {
+            "--chinese-rules"
+        },
+        if ruleset.suicide() {
+            "--allow-suicide"
+        } else {
+            "--forbid-suicide"
+        }
+    ];
+    match ruleset.ko() {
+        Ko::Simple => (),
+        Ko::Positional => args.push("--positional-superko"),
+        Ko::Situational => args.push("--situational-superko")
     }
+    args
 }
 
+//Error responses to commands engines may not support are ignored
+fn optional(result: Result<(), GtpError>) -> Result<(), GtpError> {
+    match result {
+        Err(GtpError::Failed(..)) => Ok(()),
+        result => result
+    }
+}
+
+//The profile's arguments and, for GNU Go, the rules
+pub fn args(profile: &EngineProfile, ruleset: Ruleset) -> Vec<String> {
+    let mut args = profile.args.clone();
+    if profile.gnugo_rules {
+        args.extend(ruleset_args(ruleset).into_iter().map(String::from));
+    }
+    args
+}
+
+pub fn spawn(profile: &EngineProfile, ruleset: Ruleset) -> Result<Client, GtpError> {
+    let args = args(profile, ruleset);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    Client::spawn(&profile.command, &args, profile.timeout())
+}
+
+//Moves sent to an engine for the game so far, one per handicap stone
+pub fn move_count(game: &Game) -> usize {
+    game.moves.iter().map(|entry| match &entry.action {
+        Action::Handicap(positions) => positions.len(),
+        Action::Play(_) | Action::Pass => 1,
+        Action::Resign => 0
+    }).sum()
+}
+
+//Tell the engine about handicap stones, with fixed_handicap if they are on the star points
+async fn handicap(client: &mut Client, positions: &[usize], fixed: bool) -> Result<(), GtpError> {
+    if fixed {
+        let mut placed = client.fixed_handicap(positions.len() as u32).await?;
+        let mut expected = positions.to_vec();
+        placed.sort();
+        expected.sort();
+        if placed == expected {
+            return Ok(())
+        }
+        //The engine has other star points, start over with the game's stones
+        client.clear_board().await?;
+    }
+    for &position in positions {
+        client.play(Stone::Black, Vertex::Point(position)).await?;
+    }
+    Ok(())
+}
+
+//Set up the game's position on an engine
+pub async fn replay(client: &mut Client, game: &Game) -> Result<(), GtpError> {
+    client.boardsize(game.width as u32).await?;
+    client.clear_board().await?;
+    client.komi(game.komi).await?;
+    for entry in &game.moves {
+        match &entry.action {
+            Action::Handicap(positions) => {
+                handicap(client, positions, game.placement == Placement::Fixed).await?;
+            },
+            Action::Play(position) => client.play(entry.stone, Vertex::Point(*position)).await?,
+            Action::Pass => client.play(entry.stone, Vertex::Pass).await?,
+            Action::Resign => ()
+        }
+    }
+    Ok(())
+}
+
+//Dead stones and result of a finished game, from a scoring-only engine
+pub async fn estimate(
+    pool: &Pool,
+    profile: &EngineProfile,
+    game: &Game
+) -> Result<(Vec<usize>, GameResult), PoolError> {
+    let mut engine = pool.checkout(profile, game.ruleset).await?;
+    let client = &mut engine.client;
+    replay(client, game).await?;
+    let dead = client.final_status_list(Status::Dead).await?;
+    let score = client.final_score().await?;
+    pool.checkin(engine);
+    Ok((dead, score))
+}
+
+//Stones an engine considers dead at the end of the game
+pub async fn dead_stones(pool: &Pool, profile: &EngineProfile, game: &Game) -> Result<Vec<usize>, PoolError> {
+    let mut engine = pool.checkout(profile, game.ruleset).await?;
+    let client = &mut engine.client;
+    replay(client, game).await?;
+    let dead = client.final_status_list(Status::Dead).await?;
+    pool.checkin(engine);
+    Ok(dead)
+}
+
+/*
+    Engine process playing one side, taken from the pool
+    A crash, a late or failed answer, a malformed move or no free engine
+    is reported to the session as EngineFailure
+    Engines that quit cleanly go back to the pool, unless they were still thinking
+*/
+#[allow(clippy::too_many_arguments)]
 pub fn engine(
-    command: String,
+    pool: Arc<Pool>,
+    profile: &EngineProfile,
     stone: Stone,
     board_size: u32,
-    komi: u32,
+    komi: f32,
+    ruleset: Ruleset,
+    slot: Option<Slot>, //Reserved when the game was created
     sender: mpsc::UnboundedSender<session::Message>
 ) -> mpsc::UnboundedSender<Message> {
-    let mut child = Command::new(&command)
-        .args(["--mode", "gtp", "--level", "1", "--chinese-rules", "--allow-suicide"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Error spawning child");
-    //Read from stdout
-    let stdout = child.stdout.take().unwrap();
-    let handle = tokio::spawn(async move {
-        let mut reader = BufReader::new(stdout).lines();
-        while let Ok(Some(line)) = reader.next_line().await {
-            let line = line.trim_end();
-            //println!("ENGINE: {}", line);
-            let parts: Vec<&str> = line.split(' ').collect();
-            if parts.len() == 2 {
-                //= [vertex/pass/resign]
-                let part = parts[1].to_uppercase();
-                match part.as_str() {
-                    "PASS" => if sender.send(session::Message::Pass(stone)).is_err() {
-                        break
-                    },
-                    "RESIGN" => if sender.send(session::Message::Resign(stone)).is_err() {
-                        break
-                    },
-                    _ => {
-                        let message = if let Some(index) = vertex_to_index(&part, board_size) {
-                            session::Message::Play(stone, index as usize)
-                        } else {
-                            session::Message::Resign(stone)
-                        };
-                        if sender.send(message).is_err() {
-                            break
-                        }
-                    }
-                }
-            } else if parts.len() > 2 {
-                //= vertex vertex...
-                let positions: Vec<usize> = parts[1..].iter().map(
-                    |x| vertex_to_index(x, board_size).unwrap() as usize
-                ).collect();
-                if sender.send(session::Message::Handicap(stone, positions)).is_err() {
-                    break
-                }
-            }
-        }
-    });
-    //Write to stdin
-    let mut stdin = child.stdin.take().unwrap();
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Message>();
+    let profile = profile.clone();
+    let (engine, receiver) = mpsc::unbounded_channel::<Message>();
     tokio::spawn(async move {
-        let _ = stdin.write_all(format!("boardsize {}\n", board_size).as_bytes()).await;
-        let _ = stdin.write_all(format!("komi {}\n", komi).as_bytes()).await;
-        //Listen for messages
-        while let Some(message) = receiver.recv().await {
-            match message {
-                Message::Handicap(count) => {
-                    if stdin.write_all(format!(
-                        "place_free_handicap {}\n", count
-                    ).as_bytes()).await.is_err() {
-                        break
-                    }
-                },
-                Message::Play(stone, position) => {
-                    let stone: &str = stone.into();
-                    let vertex = index_to_vertex(position, board_size);
-                    if stdin.write_all(format!(
-                        "play {} {}\n", stone, vertex
-                    ).as_bytes()).await.is_err() {
-                        break
-                    }
-                },
-                Message::Genmove => {
-                    let stone: &str = stone.into();
-                    if stdin.write_all(format!(
-                        "genmove {}\n", stone
-                    ).as_bytes()).await.is_err() {
-                        break
-                    }
+        let checkout = match slot {
+            Some(slot) => pool.checkout_reserved(&profile, ruleset, slot).await,
+            None => pool.checkout(&profile, ruleset).await
+        };
+        let result = match checkout {
+            Ok(mut engine) => match run(&mut engine.client, receiver, stone, board_size, komi, &sender).await {
+                Ok(true) => {
+                    pool.checkin(engine);
+                    Ok(())
                 },
-                Message::Quit => {
-                    if stdin.write_all(b"quit\n").await.is_err() {
-                        break
-                    } else {
-                        break
+                //Dropped, stopping the process
+                Ok(false) => Ok(()),
+                Err(error) => Err(PoolError::Engine(error))
+            },
+            Err(error) => Err(error)
+        };
+        if let Err(error) = result {
+            let _ = sender.send(session::Message::EngineFailure(stone, error));
+        }
+    });
+    engine
+}
+
+//Returns whether the engine is idle and can be reused
+async fn run(
+    client: &mut Client,
+    mut receiver: mpsc::UnboundedReceiver<Message>,
+    stone: Stone,
+    board_size: u32,
+    komi: f32,
+    sender: &mpsc::UnboundedSender<session::Message>
+) -> Result<bool, GtpError> {
+    client.boardsize(board_size).await?;
+    client.clear_board().await?;
+    client.komi(komi).await?;
+    //Moves on the engine's board, including its own the session may not have accepted
+    let mut moves = 0;
+    //Messages that arrived while the engine was thinking
+    let mut queue = VecDeque::new();
+    //Listen for messages
+    loop {
+        let message = match queue.pop_front() {
+            Some(message) => message,
+            None => match receiver.recv().await {
+                Some(message) => message,
+                None => break
+            }
+        };
+        let reply = match message {
+            Message::Play(stone, vertex) => {
+                client.play(stone, vertex).await?;
+                moves += 1;
+                continue
+            },
+            Message::Genmove => {
+                let genmove = client.genmove(stone);
+                tokio::pin!(genmove);
+                //Quit stops waiting for the engine, a takeback discards its move
+                let mut discard = false;
+                let result = loop {
+                    tokio::select! {
+                        result = &mut genmove => break result?,
+                        message = receiver.recv() => match message {
+                            None | Some(Message::Quit) => return Ok(false),
+                            Some(message) => {
+                                discard |= matches!(message, Message::Undo(_));
+                                queue.push_back(message);
+                            }
+                        }
                     }
+                };
+                let reply = match result {
+                    Genmove::Move(vertex) => {
+                        moves += 1;
+                        match vertex {
+                            Vertex::Point(position) => session::Message::Play(stone, position),
+                            Vertex::Pass => session::Message::Pass(stone)
+                        }
+                    },
+                    Genmove::Resign => session::Message::Resign(stone)
+                };
+                if discard {
+                    continue
                 }
-            }
+                reply
+            },
+            Message::Handicap(count) => {
+                let positions = client.place_free_handicap(count).await?;
+                moves += positions.len();
+                session::Message::Handicap(stone, positions)
+            },
+            Message::FixedHandicap(positions) => {
+                handicap(client, &positions, true).await?;
+                moves += positions.len();
+                continue
+            },
+            Message::Undo(target) => {
+                while moves > target {
+                    client.undo().await?;
+                    moves -= 1;
+                }
+                continue
+            },
+            Message::TimeSettings(time_control) => {
+                optional(client.command(&time_settings(time_control)).await.map(|_| ()))?;
+                continue
+            },
+            Message::TimeLeft(time, stones) => {
+                optional(client.time_left(stone, time, stones).await)?;
+                continue
+            },
+            Message::Quit => break
+        };
+        if sender.send(reply).is_err() {
+            break
         }
-        handle.abort(); //Stop stdout reader
-    });
-    sender
+    }
+    Ok(true)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{index_to_vertex, vertex_to_index};
+    use super::*;
+    use tokio::io::{duplex, split, AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use std::time::Duration;
     #[test]
-    fn test_index_to_vertex() {
-        //Corners
-        assert_eq!(index_to_vertex(0, 19), "A19");
-        assert_eq!(index_to_vertex(18, 19), "T19");
-        assert_eq!(index_to_vertex(342, 19), "A1");
-        assert_eq!(index_to_vertex(360, 19), "T1");
-        //Edges
-        assert_eq!(index_to_vertex(8, 19), "J19");
-        //Center
-        assert_eq!(index_to_vertex(180, 19), "K10");
+    fn test_time_settings() {
+        assert_eq!(time_settings(TimeControl::Absolute{main: 600}), "time_settings 600 0 0");
+        assert_eq!(
+            time_settings(TimeControl::Byoyomi{main: 600, periods: 5, period: 30}),
+            "kgs-time_settings byoyomi 600 30 5"
+        );
+        assert_eq!(
+            time_settings(TimeControl::Canadian{main: 0, stones: 25, period: 300}),
+            "time_settings 0 300 25"
+        );
     }
-    #[test]
-    fn test_vertex_to_index() {
-        //Corners
-        assert_eq!(vertex_to_index("A19", 19), Some(0));
-        assert_eq!(vertex_to_index("T19", 19), Some(18));
-        assert_eq!(vertex_to_index("A1", 19), Some(342));
-        assert_eq!(vertex_to_index("T1", 19), Some(360));
-        //Center
-        assert_eq!(vertex_to_index("K10", 19), Some(180));
+    //Client for an engine that places its fixed handicap stones at the given vertices
+    //Commands are sent back without their ids
+    fn scripted(stones: &'static str) -> (Client, mpsc::UnboundedReceiver<String>) {
+        let (client_side, engine_side) = duplex(1024);
+        let (reader, writer) = split(client_side);
+        let client = Client::new(Box::new(reader), Box::new(writer), Duration::from_secs(1));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (engine_reader, mut engine_writer) = split(engine_side);
+        tokio::spawn(async move {
+            let mut commands = BufReader::new(engine_reader).lines();
+            while let Ok(Some(line)) = commands.next_line().await {
+                let (id, command) = line.split_once(' ').unwrap();
+                let text = if command.starts_with("fixed_handicap") {stones} else {""};
+                let _ = engine_writer.write_all(format!("={} {}\n\n", id, text).as_bytes()).await;
+                let _ = sender.send(command.to_string());
+            }
+        });
+        (client, receiver)
+    }
+    #[tokio::test]
+    async fn test_handicap() {
+        //Same star points as the game
+        let (mut client, mut commands) = scripted("C3 G7");
+        client.boardsize(9).await.unwrap();
+        handicap(&mut client, &[56, 24], true).await.unwrap();
+        drop(client);
+        let mut sent = Vec::new();
+        while let Some(command) = commands.recv().await {
+            sent.push(command);
+        }
+        assert_eq!(sent, ["boardsize 9", "fixed_handicap 2"]);
+        //Different star points are replaced by the game's stones
+        let (mut client, mut commands) = scripted("C7 G3");
+        client.boardsize(9).await.unwrap();
+        handicap(&mut client, &[56, 24], true).await.unwrap();
+        drop(client);
+        let mut sent = Vec::new();
+        while let Some(command) = commands.recv().await {
+            sent.push(command);
+        }
+        assert_eq!(sent, ["boardsize 9", "fixed_handicap 2", "clear_board", "play black C3", "play black G7"]);
+    }
+    //Engine that answers genmove with C3 once released, and everything else right away
+    //Commands are sent back without their ids
+    fn thinking(release: mpsc::UnboundedReceiver<()>) -> (Client, mpsc::UnboundedReceiver<String>) {
+        let (client_side, engine_side) = duplex(1024);
+        let (reader, writer) = split(client_side);
+        let client = Client::new(Box::new(reader), Box::new(writer), Duration::from_secs(5));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (engine_reader, mut engine_writer) = split(engine_side);
+        let mut release = release;
+        tokio::spawn(async move {
+            let mut commands = BufReader::new(engine_reader).lines();
+            while let Ok(Some(line)) = commands.next_line().await {
+                let (id, command) = line.split_once(' ').unwrap();
+                let _ = sender.send(command.to_string());
+                let text = if command.starts_with("genmove") {
+                    release.recv().await;
+                    "C3"
+                } else {
+                    ""
+                };
+                let _ = engine_writer.write_all(format!("={} {}\n\n", id, text).as_bytes()).await;
+            }
+        });
+        (client, receiver)
+    }
+    #[tokio::test]
+    async fn test_genmove_cancel() {
+        //A takeback while the engine thinks discards its move and takes it back
+        let (release, released) = mpsc::unbounded_channel();
+        let (mut client, mut commands) = thinking(released);
+        let (messages, receiver) = mpsc::unbounded_channel();
+        let (session, mut replies) = mpsc::unbounded_channel();
+        messages.send(Message::Genmove).unwrap();
+        messages.send(Message::Undo(0)).unwrap();
+        let engine = tokio::spawn(async move {
+            run(&mut client, receiver, Stone::Black, 9, 6.5, &session).await
+        });
+        for expected in ["boardsize 9", "clear_board", "komi 6.5", "genmove black"] {
+            assert_eq!(commands.recv().await.unwrap(), expected);
+        }
+        release.send(()).unwrap();
+        assert_eq!(commands.recv().await.unwrap(), "undo");
+        messages.send(Message::Quit).unwrap();
+        assert!(engine.await.unwrap().unwrap());
+        assert!(replies.recv().await.is_none());
+        //Quit doesn't wait for the move, and the busy engine isn't reused
+        let (_release, released) = mpsc::unbounded_channel();
+        let (mut client, mut commands) = thinking(released);
+        let (messages, receiver) = mpsc::unbounded_channel();
+        let (session, _replies) = mpsc::unbounded_channel();
+        messages.send(Message::Genmove).unwrap();
+        messages.send(Message::Quit).unwrap();
+        let engine = tokio::spawn(async move {
+            run(&mut client, receiver, Stone::Black, 9, 6.5, &session).await
+        });
+        assert!(!tokio::time::timeout(Duration::from_secs(1), engine).await.unwrap().unwrap().unwrap());
+        assert_eq!(commands.recv().await.unwrap(), "boardsize 9");
     }
 }
@@ -0,0 +1,420 @@
+use crate::{
+    model::{GameResult, Stone},
+    coord::{Coord, CoordError}
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    process::{Child, Command},
+    time
+};
+use std::{
+    error,
+    fmt,
+    io,
+    process::Stdio,
+    time::Duration
+};
+
+/*
+    Go Text Protocol client
+    Commands are numbered and sent one at a time, each response is
+    "=[id] text" or "?[id] message" followed by a blank line
+    Board positions are indices into the board, row by row from the top left
+*/
+
+pub enum GtpError {
+    Spawn(io::Error),
+    Io(io::Error),
+    Exited,
+    Timeout(String),
+    //Command and error message
+    Failed(String, String),
+    Malformed(String),
+    Vertex(CoordError)
+}
+
+impl fmt::Display for GtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GtpError::Spawn(error) => write!(f, "Cannot start engine: {}", error),
+            GtpError::Io(error) => write!(f, "Engine I/O error: {}", error),
+            GtpError::Exited => write!(f, "Engine exited"),
+            GtpError::Timeout(command) => write!(f, "No response to {}", command),
+            GtpError::Failed(command, message) => write!(f, "{} failed: {}", command, message),
+            GtpError::Malformed(response) => write!(f, "Malformed response: {}", response),
+            GtpError::Vertex(error) => write!(f, "Invalid vertex: {}", error)
+        }
+    }
+}
+
+impl fmt::Debug for GtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for GtpError {}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Vertex {
+    Pass,
+    Point(usize)
+}
+
+//Result of genmove
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Genmove {
+    Move(Vertex),
+    Resign
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Status {
+    Alive,
+    Dead,
+    Seki
+}
+
+//Columns A to Z without I, rows counted from the bottom
+pub fn index_to_vertex(index: usize, width: usize, height: usize) -> Result<String, CoordError> {
+    Coord::from_index(index, width, height).map(Coord::gtp)
+}
+
+fn vertex_to_index(vertex: &str, width: usize, height: usize) -> Result<usize, CoordError> {
+    Coord::parse_gtp(vertex, width, height).map(Coord::index)
+}
+
+fn parse_vertex(text: &str, board_size: usize) -> Result<Vertex, CoordError> {
+    if text.eq_ignore_ascii_case("pass") {
+        Ok(Vertex::Pass)
+    } else {
+        vertex_to_index(text, board_size, board_size).map(Vertex::Point)
+    }
+}
+
+fn parse_vertices(text: &str, board_size: usize) -> Result<Vec<usize>, GtpError> {
+    text.split_whitespace()
+        .map(|vertex| vertex_to_index(vertex, board_size, board_size))
+        .collect::<Result<Vec<usize>, CoordError>>()
+        .map_err(GtpError::Vertex)
+}
+
+//Score as "B+3.5", "W+12" or "0"
+fn parse_score(text: &str) -> Option<GameResult> {
+    if text == "0" {
+        return Some(GameResult::Draw)
+    }
+    let (color, margin) = text.split_once('+')?;
+    let winner = match color.to_ascii_uppercase().as_str() {
+        "B" => Stone::Black,
+        "W" => Stone::White,
+        _ => return None
+    };
+    let margin: f32 = margin.parse().ok()?;
+    if margin == 0.0 {
+        Some(GameResult::Draw)
+    } else {
+        Some(GameResult::Score {winner, margin})
+    }
+}
+
+//GTP response: "=[id] text" on success, "?[id] message" on failure
+#[derive(PartialEq, Debug)]
+struct Response {
+    id: Option<u32>,
+    success: bool,
+    text: String
+}
+
+//Parse the lines of one response, without the blank line ending it
+fn parse_response(lines: &[String]) -> Option<Response> {
+    let first = lines.first()?;
+    let success = match first.chars().next()? {
+        '=' => true,
+        '?' => false,
+        _ => return None
+    };
+    let rest = &first[1..];
+    let digits = rest.chars().take_while(char::is_ascii_digit).count();
+    let id = if digits > 0 {
+        Some(rest[..digits].parse().ok()?)
+    } else {
+        None
+    };
+    let mut text = rest[digits..].trim().to_string();
+    for line in &lines[1..] {
+        text.push('\n');
+        text.push_str(line.trim_end());
+    }
+    Some(Response {id, success, text})
+}
+
+pub struct Client {
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    reader: Lines<BufReader<Box<dyn AsyncRead + Unpin + Send>>>,
+    //Engine process, killed when the client is dropped
+    child: Option<Child>,
+    next_id: u32,
+    board_size: usize,
+    timeout: Duration
+}
+
+impl Client {
+    pub fn new(
+        reader: Box<dyn AsyncRead + Unpin + Send>,
+        writer: Box<dyn AsyncWrite + Unpin + Send>,
+        timeout: Duration
+    ) -> Client {
+        Client {
+            writer,
+            reader: BufReader::new(reader).lines(),
+            child: None,
+            next_id: 1,
+            board_size: 19,
+            timeout
+        }
+    }
+    pub fn spawn(command: &str, args: &[&str], timeout: Duration) -> Result<Client, GtpError> {
+        let mut child = Command::new(command)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(GtpError::Spawn)?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let mut client = Client::new(Box::new(stdout), Box::new(stdin), timeout);
+        client.child = Some(child);
+        Ok(client)
+    }
+    //Send any command and wait for the response text
+    pub async fn command(&mut self, command: &str) -> Result<String, GtpError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.writer.write_all(format!("{} {}\n", id, command).as_bytes()).await
+            .map_err(GtpError::Io)?;
+        self.writer.flush().await.map_err(GtpError::Io)?;
+        let response = time::timeout(self.timeout, self.response()).await
+            .map_err(|_| GtpError::Timeout(command.to_string()))??;
+        if response.id.is_some_and(|response| response != id) {
+            return Err(GtpError::Malformed(response.text))
+        }
+        if response.success {
+            Ok(response.text)
+        } else {
+            Err(GtpError::Failed(command.to_string(), response.text))
+        }
+    }
+    async fn response(&mut self) -> Result<Response, GtpError> {
+        let mut lines = Vec::<String>::new();
+        loop {
+            let line = self.reader.next_line().await
+                .map_err(GtpError::Io)?
+                .ok_or(GtpError::Exited)?;
+            let line = line.replace('\t', " ");
+            let line = line.trim_end();
+            if line.starts_with('#') {
+                continue
+            }
+            if !line.is_empty() {
+                lines.push(line.to_string());
+            } else if !lines.is_empty() {
+                //Blank line ends the response
+                return parse_response(&lines).ok_or_else(|| GtpError::Malformed(lines.join(" ")))
+            }
+        }
+    }
+    pub async fn boardsize(&mut self, board_size: u32) -> Result<(), GtpError> {
+        self.command(&format!("boardsize {}", board_size)).await?;
+        self.board_size = board_size as usize;
+        Ok(())
+    }
+    pub async fn clear_board(&mut self) -> Result<(), GtpError> {
+        self.command("clear_board").await.map(|_| ())
+    }
+    pub async fn komi(&mut self, komi: f32) -> Result<(), GtpError> {
+        self.command(&format!("komi {}", komi)).await.map(|_| ())
+    }
+    pub async fn play(&mut self, stone: Stone, vertex: Vertex) -> Result<(), GtpError> {
+        let color: &str = stone.into();
+        let vertex = match vertex {
+            Vertex::Pass => "pass".to_string(),
+            Vertex::Point(index) => index_to_vertex(index, self.board_size, self.board_size)
+                .map_err(GtpError::Vertex)?
+        };
+        self.command(&format!("play {} {}", color, vertex)).await.map(|_| ())
+    }
+    pub async fn genmove(&mut self, stone: Stone) -> Result<Genmove, GtpError> {
+        let color: &str = stone.into();
+        let text = self.command(&format!("genmove {}", color)).await?;
+        if text.eq_ignore_ascii_case("resign") {
+            Ok(Genmove::Resign)
+        } else {
+            parse_vertex(&text, self.board_size)
+                .map(Genmove::Move)
+                .map_err(GtpError::Vertex)
+        }
+    }
+    pub async fn place_free_handicap(&mut self, count: u32) -> Result<Vec<usize>, GtpError> {
+        let text = self.command(&format!("place_free_handicap {}", count)).await?;
+        parse_vertices(&text, self.board_size)
+    }
+    pub async fn fixed_handicap(&mut self, count: u32) -> Result<Vec<usize>, GtpError> {
+        let text = self.command(&format!("fixed_handicap {}", count)).await?;
+        parse_vertices(&text, self.board_size)
+    }
+    pub async fn undo(&mut self) -> Result<(), GtpError> {
+        self.command("undo").await.map(|_| ())
+    }
+    pub async fn final_score(&mut self) -> Result<GameResult, GtpError> {
+        let text = self.command("final_score").await?;
+        parse_score(&text).ok_or(GtpError::Malformed(text))
+    }
+    pub async fn final_status_list(&mut self, status: Status) -> Result<Vec<usize>, GtpError> {
+        let status = match status {
+            Status::Alive => "alive",
+            Status::Dead => "dead",
+            Status::Seki => "seki"
+        };
+        let text = self.command(&format!("final_status_list {}", status)).await?;
+        parse_vertices(&text, self.board_size)
+    }
+    //Seconds left and stones to play in the current period (0 in main time)
+    pub async fn time_left(&mut self, stone: Stone, time: u32, stones: u32) -> Result<(), GtpError> {
+        let color: &str = stone.into();
+        self.command(&format!("time_left {} {} {}", color, time, stones)).await.map(|_| ())
+    }
+    pub async fn showboard(&mut self) -> Result<String, GtpError> {
+        self.command("showboard").await
+    }
+    pub async fn list_commands(&mut self) -> Result<Vec<String>, GtpError> {
+        let text = self.command("list_commands").await?;
+        Ok(text.lines().map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect())
+    }
+    //Ask the engine to exit, waiting briefly before it is killed
+    pub async fn quit(mut self) {
+        let _ = time::timeout(Duration::from_secs(1), self.command("quit")).await;
+        if let Some(child) = self.child.as_mut() {
+            let _ = time::timeout(Duration::from_secs(1), child.wait()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, split};
+    #[test]
+    fn test_index_to_vertex() {
+        //Corners
+        assert_eq!(index_to_vertex(0, 19, 19).unwrap(), "A19");
+        assert_eq!(index_to_vertex(18, 19, 19).unwrap(), "T19");
+        assert_eq!(index_to_vertex(342, 19, 19).unwrap(), "A1");
+        assert_eq!(index_to_vertex(360, 19, 19).unwrap(), "T1");
+        //Edges
+        assert_eq!(index_to_vertex(8, 19, 19).unwrap(), "J19");
+        //Center
+        assert_eq!(index_to_vertex(180, 19, 19).unwrap(), "K10");
+        //Largest and smallest boards
+        assert_eq!(index_to_vertex(24, 25, 25).unwrap(), "Z25");
+        assert_eq!(index_to_vertex(624, 25, 25).unwrap(), "Z1");
+        assert_eq!(index_to_vertex(3, 2, 2).unwrap(), "B1");
+        //Rectangular, 3 wide and 5 high
+        assert_eq!(index_to_vertex(2, 3, 5).unwrap(), "C5");
+        assert_eq!(index_to_vertex(12, 3, 5).unwrap(), "A1");
+        //Off the board
+        assert!(index_to_vertex(15, 3, 5).is_err());
+        assert!(index_to_vertex(0, 26, 26).is_err());
+    }
+    #[test]
+    fn test_vertex_to_index() {
+        //Corners
+        assert_eq!(vertex_to_index("A19", 19, 19), Ok(0));
+        assert_eq!(vertex_to_index("T19", 19, 19), Ok(18));
+        assert_eq!(vertex_to_index("A1", 19, 19), Ok(342));
+        assert_eq!(vertex_to_index("T1", 19, 19), Ok(360));
+        //Center
+        assert_eq!(vertex_to_index("K10", 19, 19), Ok(180));
+        //Largest and smallest boards
+        assert_eq!(vertex_to_index("Z1", 25, 25), Ok(624));
+        assert_eq!(vertex_to_index("B2", 2, 2), Ok(1));
+        assert!(vertex_to_index("C3", 2, 2).is_err());
+        //Rectangular
+        assert_eq!(vertex_to_index("C5", 3, 5), Ok(2));
+        assert!(vertex_to_index("D1", 3, 5).is_err());
+        assert!(vertex_to_index("A6", 3, 5).is_err());
+        //Malformed
+        assert!(vertex_to_index("", 19, 19).is_err());
+        assert!(vertex_to_index("I5", 19, 19).is_err());
+        assert!(vertex_to_index("K0", 19, 19).is_err());
+        assert!(vertex_to_index("K10", 9, 9).is_err());
+        assert!(vertex_to_index("5", 19, 19).is_err());
+    }
+    #[test]
+    fn test_parse_response() {
+        let lines = |text: &str| text.lines().map(String::from).collect::<Vec<String>>();
+        assert_eq!(parse_response(&lines("=3 D4")), Some(Response {
+            id: Some(3),
+            success: true,
+            text: "D4".to_string()
+        }));
+        assert_eq!(parse_response(&lines("= ")), Some(Response {
+            id: None,
+            success: true,
+            text: String::new()
+        }));
+        assert_eq!(parse_response(&lines("?12 illegal move")), Some(Response {
+            id: Some(12),
+            success: false,
+            text: "illegal move".to_string()
+        }));
+        assert_eq!(parse_response(&lines("=1 D4 Q16\nQ4")).unwrap().text, "D4 Q16\nQ4");
+        assert_eq!(parse_response(&lines("Loading model")), None);
+    }
+    #[test]
+    fn test_parse_score() {
+        assert_eq!(parse_score("B+3.5"), Some(GameResult::Score {winner: Stone::Black, margin: 3.5}));
+        assert_eq!(parse_score("W+12"), Some(GameResult::Score {winner: Stone::White, margin: 12.0}));
+        assert_eq!(parse_score("0"), Some(GameResult::Draw));
+        assert_eq!(parse_score("X+1"), None);
+    }
+    #[tokio::test]
+    async fn test_client() {
+        let (client_side, engine_side) = duplex(1024);
+        let (reader, writer) = split(client_side);
+        let mut client = Client::new(Box::new(reader), Box::new(writer), Duration::from_secs(1));
+        //Scripted engine: answers in order, ignoring the commands
+        let (engine_reader, mut engine_writer) = split(engine_side);
+        tokio::spawn(async move {
+            let mut commands = BufReader::new(engine_reader).lines();
+            let responses = [
+                "=1\n\n",
+                "=2 C3\n\n",
+                "# comment\n=3\n\n",
+                "?4 illegal move\n\n",
+                "=5 known_command\nlist_commands\n\n",
+                "=6 B4 D2\n\n",
+                "=7 W+0.5\n\n",
+                "=99\n\n"
+            ];
+            for response in responses {
+                if commands.next_line().await.ok().flatten().is_none() {
+                    break
+                }
+                let _ = engine_writer.write_all(response.as_bytes()).await;
+            }
+        });
+        client.boardsize(5).await.unwrap();
+        assert_eq!(client.genmove(Stone::Black).await.unwrap(), Genmove::Move(Vertex::Point(12)));
+        client.play(Stone::White, Vertex::Pass).await.unwrap();
+        assert!(matches!(client.play(Stone::White, Vertex::Point(12)).await, Err(GtpError::Failed(..))));
+        assert_eq!(client.list_commands().await.unwrap(), ["known_command", "list_commands"]);
+        assert_eq!(client.final_status_list(Status::Dead).await.unwrap(), [6, 18]);
+        assert_eq!(client.final_score().await.unwrap(), GameResult::Score {winner: Stone::White, margin: 0.5});
+        //Mismatched id
+        assert!(matches!(client.clear_board().await, Err(GtpError::Malformed(..))));
+        //Engine gone
+        assert!(matches!(client.undo().await, Err(GtpError::Exited | GtpError::Io(_))));
+    }
+}
@@ -1,87 +1,355 @@
 use crate::{
-    model::{Stone, Game},
-    session::{self, Packet, Message}
+    model::{Action, Stone, Game, Placement, Ruleset, Turn},
+    session::{self, Channel, Event, Message},
+    timer::{Clocks, TimeControl},
+    storage::Storage,
+    config::{Config, Limits},
+    analysis::{Analysis, AnalysisError, Analyzer, RateLimiter},
+    pool::{Pool, PoolError, PoolStats, Slot},
+    gtp,
+    coord::CoordError,
+    sgf
 };
 use axum::{
+    Json,
     response::{self, Response, sse},
     extract::{
         Form,
+        Multipart,
         State,
         Path,
+        Query,
         ws
     }
 };
-use http::status::StatusCode;
-use tokio::sync::oneshot;
+use http::{status::StatusCode, header};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio_stream::wrappers::{
     BroadcastStream,
     errors::BroadcastStreamRecvError
 };
 use futures::{
+    future,
     sink::SinkExt,
-    stream::{Stream, StreamExt}
+    stream::{self, Stream, StreamExt}
 };
 use handlebars::Handlebars;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
 use std::{
-    sync::{Arc, Mutex},
-    time::Duration
+    collections::VecDeque,
+    convert::Infallible,
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicBool, Ordering}
+    },
+    time::{Duration, Instant}
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub templates: Handlebars<'static>,
     pub sessions: Arc<Mutex<session::Sessions>>,
-    pub engine: String
+    pub config: Arc<Config>,
+    pub storage: Arc<Storage>,
+    pub shutting_down: Arc<AtomicBool>,
+    //Engine processes for all games, hints and scoring
+    pub pool: Arc<Pool>,
+    //Hints are off without an analysis engine
+    pub analyzer: Option<Arc<Analyzer>>,
+    pub hint_limiter: Arc<RateLimiter>
 }
 
 #[derive(Deserialize)]
 pub struct CreateGameForm {
     board_size: usize,
-    komi: u32,
+    #[serde(default, deserialize_with = "empty_as_zero")]
+    board_height: usize,
+    komi: f32,
     handicap: u32,
-    fixed_time: u64,
-    added_time: u64,
+    placement: Placement,
+    ruleset: Ruleset,
+    time_system: String,
+    fixed_time: u32,
+    added_time: u32,
+    #[serde(default, deserialize_with = "empty_as_zero")]
+    periods: u32,
+    #[serde(default, deserialize_with = "empty_as_zero")]
+    time_cap: u32,
     black_player: String,
-    white_player: String
+    white_player: String,
+    #[serde(default, deserialize_with = "empty_as_zero")]
+    move_delay: u64,
+    #[serde(default)]
+    rated: bool,
+    #[serde(default)]
+    takebacks: bool
+}
+
+//Number inputs left empty are submitted as empty strings
+fn empty_as_zero<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Default,
+    T::Err: fmt::Display
+{
+    let text = String::deserialize(deserializer)?;
+    match text.trim() {
+        "" => Ok(T::default()),
+        text => text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/*
+    Board fields:
+    board_size: Width, and height of square boards
+    board_height: Height of rectangular boards (0 for square, optional)
+    Time control fields:
+    time_system: absolute, fischer, byoyomi or canadian
+    fixed_time: Main time
+    added_time: Fischer increment or overtime period
+    periods: Byo-yomi periods or Canadian stones per period
+    time_cap: Fischer maximum (0 for none)
+    move_delay: Seconds before each bot move (optional)
+    rated: "true" for games without engine hints (optional)
+    takebacks: "true" to let players take back moves (optional)
+*/
+fn time_control(
+    limits: &Limits,
+    system: &str,
+    fixed_time: u32,
+    added_time: u32,
+    periods: u32,
+    time_cap: u32
+) -> Option<TimeControl> {
+    let time_control = match system {
+        "absolute" => TimeControl::Absolute{main: fixed_time},
+        "fischer" => TimeControl::Fischer{
+            main: fixed_time,
+            increment: added_time,
+            cap: if time_cap > 0 {Some(time_cap)} else {None}
+        },
+        "byoyomi" => TimeControl::Byoyomi{main: fixed_time, periods, period: added_time},
+        "canadian" => TimeControl::Canadian{main: fixed_time, stones: periods, period: added_time},
+        _ => return None
+    };
+    if time_control.valid()
+    && time_control.main() <= limits.max_fixed_time
+    && time_control.added() <= limits.max_added_time {
+        Some(time_control)
+    } else {
+        None
+    }
+}
+
+/*
+    Player fields:
+    human: Human player
+    bot: Default engine profile
+    Any other value: Engine profile ID
+    Engines only play on square boards
+*/
+fn players(
+    config: &Config,
+    black_player: &str,
+    white_player: &str,
+    width: u32,
+    height: u32
+) -> Result<[Option<String>; 2], &'static str> {
+    let engine = |player: &str| {
+        //The default profile is checked like any other
+        let id = match player {
+            "human" => return Ok(None),
+            "bot" => config.default_engine.as_str(),
+            id => id
+        };
+        match config.engines.get(id) {
+            Some(_) if width != height => Err("Engines only play on square boards"),
+            Some(profile) if profile.supports(width) => Ok(Some(id.to_string())),
+            Some(_) => Err("Board size not supported by engine"),
+            None => Err("Unknown engine")
+        }
+    };
+    Ok([engine(black_player)?, engine(white_player)?])
+}
+
+//Pause before each bot move, in seconds
+fn move_delay(limits: &Limits, seconds: u64) -> Option<Duration> {
+    if seconds <= limits.max_move_delay {
+        Some(Duration::from_secs(seconds))
+    } else {
+        None
+    }
+}
+
+fn board_size_allowed(limits: &Limits, width: u32, height: u32) -> bool {
+    let range = limits.min_board_size..=limits.max_board_size;
+    range.contains(&width) && range.contains(&height)
+}
+
+//Bot games are refused while the engine pool is full
+//Pool slots for the bots of a new game, held until their engines start
+fn reserve_engines(state: &AppState, engines: &[Option<String>; 2], ruleset: Ruleset) -> Option<[Option<Slot>; 2]> {
+    let profiles: Vec<_> = engines.iter().flatten()
+        .map(|id| (&state.config.engines[id], ruleset))
+        .collect();
+    if profiles.is_empty() {
+        return Some([None, None])
+    }
+    let mut slots = state.pool.reserve(&profiles)?.into_iter();
+    Some(engines.clone().map(|engine| engine.and_then(|_| slots.next())))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_session(
+    state: AppState,
+    game: Game,
+    engines: [Option<String>; 2],
+    reserved: [Option<Slot>; 2],
+    time_control: TimeControl,
+    move_delay: Duration,
+    rated: bool,
+    takebacks: bool
+) -> usize {
+    let id: usize = {
+        let sessions = state.sessions.lock().unwrap();
+        //Generate session ID
+        loop {
+            let id: usize = rand::random();
+            if !sessions.contains_key(&id) {
+                break id;
+            }
+        }
+    };
+    start_session(state, id, game, engines, reserved, Clocks::new(time_control), move_delay, rated, takebacks);
+    id
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start_session(
+    state: AppState,
+    id: usize,
+    game: Game,
+    engines: [Option<String>; 2],
+    reserved: [Option<Slot>; 2],
+    clocks: Clocks,
+    move_delay: Duration,
+    rated: bool,
+    takebacks: bool
+) {
+    let mut sessions = state.sessions.lock().unwrap();
+    //Spawn task
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<Message>();
+    //Room for bursts of chat between moves
+    let (broadcast, _) = tokio::sync::broadcast::channel::<Event>(32);
+    tokio::spawn(session::session(
+        id,
+        state.sessions.clone(),
+        sender.clone(),
+        receiver,
+        broadcast.clone(),
+        game,
+        engines,
+        reserved,
+        clocks,
+        move_delay,
+        rated,
+        takebacks,
+        state.config.clone(),
+        state.storage.clone(),
+        state.pool.clone()
+    ));
+    //Register session 
+    sessions.insert(id, (sender, broadcast));
+}
+
+//Restart sessions saved at the last shutdown
+//Clocks stay paused until both players reconnect
+pub fn resume_sessions(state: &AppState) {
+    let snapshots = match state.storage.take_snapshots() {
+        Ok(snapshots) => snapshots,
+        Err(error) => {
+            eprintln!("Error loading snapshots: {}", error);
+            return
+        }
+    };
+    for snapshot in snapshots {
+        let game = match state.storage.load(snapshot.id) {
+            Ok(Some(record)) => record.game(),
+            _ => continue
+        };
+        match game {
+            Ok(game) => start_session(
+                state.clone(),
+                snapshot.id,
+                game,
+                snapshot.engines,
+                //Bots of resumed games wait for free engines
+                [None, None],
+                snapshot.clocks,
+                snapshot.move_delay,
+                snapshot.rated,
+                snapshot.takebacks
+            ),
+            Err(error) => eprintln!("Error resuming game {}: {}", snapshot.id, error)
+        }
+    }
+}
+
+//Stop every session at once, saving those still in progress
+//Clients are told the server is shutting down, sessions still running after the drain period are dropped
+pub async fn suspend_sessions(state: &AppState, drain: Duration) {
+    let sessions: Vec<_> = {
+        let sessions = state.sessions.lock().unwrap();
+        sessions.values().map(|(session, _)| session.clone()).collect()
+    };
+    let count = sessions.len();
+    let suspended = sessions.into_iter().map(|session| async move {
+        let (sender, receiver) = oneshot::channel();
+        if session.send(Message::Snapshot(sender)).is_err() {
+            return
+        }
+        if let Ok(Some(snapshot)) = receiver.await {
+            if let Err(error) = state.storage.save_snapshot(&snapshot) {
+                eprintln!("Error saving snapshot {}: {}", snapshot.id, error);
+            }
+        }
+    });
+    if tokio::time::timeout(drain, future::join_all(suspended)).await.is_err() {
+        eprintln!("Not all of {} sessions stopped within {} seconds", count, drain.as_secs());
+    }
 }
 
 pub async fn create_session(
     State(state): State<AppState>,
     Form(form): Form<CreateGameForm>
 ) -> response::Result<(StatusCode, response::Redirect), StatusCode> {
+    if state.shutting_down.load(Ordering::Relaxed) {
+        return Err(StatusCode::SERVICE_UNAVAILABLE)
+    }
     //Create game
-    let bots = [
-        form.black_player == "bot",
-        form.white_player == "bot"
-    ];
-    if form.fixed_time <= 3600 && form.added_time <= 60 && (!bots[0] || !bots[1]) {
-        if let Ok(game) = Game::new(form.board_size, form.komi, form.handicap) {
-            let mut sessions = state.sessions.lock().unwrap();
-            //Generate session ID
-            let id: usize = loop {
-                let id: usize = rand::random();
-                if !sessions.contains_key(&id) {
-                    break id;
-                }
-            };
-            //Spawn task
-            let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<Message>();
-            let (broadcast, _) = tokio::sync::broadcast::channel::<Packet>(8);
-            tokio::spawn(session::session(
-                id,
-                state.sessions.clone(),
-                sender.clone(),
-                receiver,
-                broadcast.clone(),
-                game,
-                bots,
-                Duration::from_secs(form.fixed_time),
-                Duration::from_secs(form.added_time),
-                state.engine
-            ));
-            //Register session 
-            sessions.insert(id, (sender, broadcast));
+    let limits = &state.config.limits;
+    let width = form.board_size;
+    let height = if form.board_height == 0 {width} else {form.board_height};
+    if !board_size_allowed(limits, width as u32, height as u32) {
+        return Err(StatusCode::BAD_REQUEST)
+    }
+    let time_control = time_control(
+        limits,
+        &form.time_system,
+        form.fixed_time,
+        form.added_time,
+        form.periods,
+        form.time_cap
+    );
+    let engines = players(&state.config, &form.black_player, &form.white_player, width as u32, height as u32);
+    let move_delay = move_delay(limits, form.move_delay);
+    if let (Some(time_control), Ok(engines), Some(move_delay)) = (time_control, engines, move_delay) {
+        if let Ok(game) = Game::rectangular(width, height, form.komi, form.handicap, form.placement, form.ruleset) {
+            let reserved = reserve_engines(&state, &engines, game.ruleset).ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+            let id = spawn_session(state, game, engines, reserved, time_control, move_delay, form.rated, form.takebacks);
             Ok((StatusCode::SEE_OTHER, response::Redirect::to(&format!("/play/{}", id))))
         } else {
             Err(StatusCode::BAD_REQUEST)
@@ -91,15 +359,145 @@ pub async fn create_session(
     }
 }
 
+/*
+    Import form fields:
+    record: SGF file
+    move: Number of moves to replay (empty for all)
+    time_system, fixed_time, added_time, periods, time_cap,
+    black_player, white_player, move_delay, rated, takebacks: As in CreateGameForm
+*/
+
+pub async fn import_session(
+    State(state): State<AppState>,
+    mut multipart: Multipart
+) -> Result<(StatusCode, response::Redirect), (StatusCode, String)> {
+    if state.shutting_down.load(Ordering::Relaxed) {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "Server shutting down".to_string()))
+    }
+    let bad_request = |message: &str| (StatusCode::BAD_REQUEST, message.to_string());
+    let mut record = None;
+    let mut limit = None;
+    let mut time_system = String::from("fischer");
+    let mut fixed_time = 0;
+    let mut added_time = 0;
+    let mut periods = 0;
+    let mut time_cap = 0;
+    let mut black_player = String::from("human");
+    let mut white_player = String::from("human");
+    let mut delay = 0;
+    let mut rated = false;
+    let mut takebacks = false;
+    while let Some(field) = multipart.next_field().await
+        .map_err(|_| bad_request("Malformed form"))? {
+        let name = field.name().unwrap_or_default().to_string();
+        let value = field.text().await.map_err(|_| bad_request("Malformed form"))?;
+        let value = value.trim();
+        match name.as_str() {
+            "record" => record = Some(value.to_string()),
+            "move" if !value.is_empty() => {
+                limit = Some(value.parse().map_err(|_| bad_request("Invalid move number"))?);
+            },
+            "time_system" => time_system = value.to_string(),
+            "fixed_time" => fixed_time = value.parse().map_err(|_| bad_request("Invalid fixed time"))?,
+            "added_time" => added_time = value.parse().map_err(|_| bad_request("Invalid added time"))?,
+            "periods" if !value.is_empty() => {
+                periods = value.parse().map_err(|_| bad_request("Invalid periods"))?;
+            },
+            "time_cap" if !value.is_empty() => {
+                time_cap = value.parse().map_err(|_| bad_request("Invalid time cap"))?;
+            },
+            "black_player" => black_player = value.to_string(),
+            "white_player" => white_player = value.to_string(),
+            "rated" => rated = value == "true",
+            "takebacks" => takebacks = value == "true",
+            "move_delay" if !value.is_empty() => {
+                delay = value.parse().map_err(|_| bad_request("Invalid move delay"))?;
+            },
+            _ => ()
+        }
+    }
+    let limits = &state.config.limits;
+    let time_control = time_control(limits, &time_system, fixed_time, added_time, periods, time_cap)
+        .ok_or_else(|| bad_request("Invalid time control"))?;
+    let move_delay = move_delay(limits, delay)
+        .ok_or_else(|| bad_request("Invalid move delay"))?;
+    let record = record.ok_or_else(|| bad_request("Missing SGF record"))?;
+    match sgf::read(&record, limit) {
+        Ok(game) => {
+            let (width, height) = (game.width as u32, game.height as u32);
+            if !board_size_allowed(limits, width, height) {
+                return Err(bad_request("Unsupported board size"))
+            }
+            let engines = players(&state.config, &black_player, &white_player, width, height)
+                .map_err(bad_request)?;
+            let reserved = reserve_engines(&state, &engines, game.ruleset)
+                .ok_or_else(|| (StatusCode::SERVICE_UNAVAILABLE, PoolError::Full.to_string()))?;
+            let id = spawn_session(state, game, engines, reserved, time_control, move_delay, rated, takebacks);
+            Ok((StatusCode::SEE_OTHER, response::Redirect::to(&format!("/play/{}", id))))
+        },
+        Err(error) => Err((StatusCode::BAD_REQUEST, error.to_string()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Metrics {
+    sessions: usize,
+    engines: PoolStats
+}
+
+//Running sessions and engine pool usage
+pub async fn metrics(State(state): State<AppState>) -> Json<Metrics> {
+    let sessions = state.sessions.lock().unwrap().len();
+    Json(Metrics {
+        sessions,
+        engines: state.pool.stats()
+    })
+}
+
+#[derive(Serialize)]
+struct EngineData {
+    id: String,
+    label: String,
+    default: bool
+}
+
+#[derive(Serialize)]
+struct IndexTemplateData {
+    engines: Vec<EngineData>
+}
+
+pub async fn index(
+    State(state): State<AppState>
+) -> response::Html<String> {
+    let config = &state.config;
+    let data = IndexTemplateData {
+        engines: config.engines.iter().map(|(id, profile)| EngineData {
+            id: id.clone(),
+            label: profile.label(),
+            default: *id == config.default_engine
+        }).collect()
+    };
+    let body = state.templates.render("index", &data).unwrap();
+    response::Html(body)
+}
+
 #[derive(Serialize, Deserialize)]
 struct GameTemplateData {
     id: usize,
     stone: String,
     board_size: u32,
-    komi: u32,
+    board_height: u32,
+    komi: f32,
     handicap: u32,
-    fixed_time: u32,
-    added_time: u32
+    placement: Placement,
+    ruleset: &'static str,
+    time_control: String,
+    black_player: String,
+    white_player: String,
+    rated: bool,
+    takebacks: bool,
+    //Analysis engine configured
+    hints: bool
 }
 
 pub async fn get_session(
@@ -108,12 +506,7 @@ pub async fn get_session(
 ) -> Result<response::Html<String>, StatusCode> {
     let session = {
         let sessions = state.sessions.lock().unwrap();
-        let session = sessions.get(&game);
-        if let Some(session) = session {
-            Some(session.clone())
-        } else {
-            None
-        }
+        sessions.get(&game).cloned()
     };
     if let Some((session, _)) = session {
         let (sender, receiver) = oneshot::channel();
@@ -124,10 +517,17 @@ pub async fn get_session(
                     id: game,
                     stone: "empty".to_string(),
                     board_size: settings.board_size,
+                    board_height: settings.height(),
                     komi: settings.komi,
                     handicap: settings.handicap,
-                    fixed_time: settings.fixed_time,
-                    added_time: settings.added_time
+                    placement: settings.placement,
+                    ruleset: settings.ruleset.into(),
+                    time_control: settings.time_control.to_string(),
+                    black_player: settings.black_player,
+                    white_player: settings.white_player,
+                    rated: settings.rated,
+                    takebacks: settings.takebacks,
+                    hints: state.analyzer.is_some()
                 };
                 let body = state.templates.render("lobby", &data).unwrap();
                 Ok(response::Html(body))
@@ -148,12 +548,7 @@ pub async fn join_session(
 ) -> Result<response::Html<String>, StatusCode> {
     let session = {
         let sessions = state.sessions.lock().unwrap();
-        let session = sessions.get(&game);
-        if let Some(session) = session {
-            Some(session.clone())
-        } else {
-            None
-        }
+        sessions.get(&game).cloned()
     };
     if let Some((session, _)) = session {
         let stone = match side.as_str() {
@@ -181,10 +576,17 @@ pub async fn join_session(
                                 id: game,
                                 stone: side,
                                 board_size: settings.board_size,
+                                board_height: settings.height(),
                                 komi: settings.komi,
                                 handicap: settings.handicap,
-                                fixed_time: settings.fixed_time,
-                                added_time: settings.added_time
+                                placement: settings.placement,
+                                ruleset: settings.ruleset.into(),
+                                time_control: settings.time_control.to_string(),
+                                black_player: settings.black_player,
+                                white_player: settings.white_player,
+                                rated: settings.rated,
+                                takebacks: settings.takebacks,
+                                hints: state.analyzer.is_some()
                             };
                             let body = state.templates.render("game", &data).unwrap();
                             Ok(response::Html(body))
@@ -208,37 +610,229 @@ pub async fn join_session(
     }
 }
 
+pub async fn export_session(
+    Path(id): Path<usize>,
+    State(state): State<AppState>
+) -> Result<([(header::HeaderName, String); 2], String), StatusCode> {
+    let session = {
+        let sessions = state.sessions.lock().unwrap();
+        sessions.get(&id).cloned()
+    };
+    let record = if let Some((session, _)) = session {
+        let (sender, receiver) = oneshot::channel();
+        let message = Message::Record(sender);
+        if session.send(message).is_err() {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        receiver.await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    } else {
+        //Finished games are exported from storage
+        let record = state.storage.load(id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let game = record.game().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        sgf::write(&game, &record.settings).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    };
+    let headers = [
+        (header::CONTENT_TYPE, "application/x-go-sgf".to_string()),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.sgf\"", id))
+    ];
+    Ok((headers, record))
+}
+
+#[derive(Serialize)]
+struct MoveData {
+    number: usize,
+    stone: &'static str,
+    action: String
+}
+
+#[derive(Serialize)]
+struct RecordTemplateData {
+    id: usize,
+    board_size: u32,
+    board_height: u32,
+    komi: f32,
+    handicap: u32,
+    placement: Placement,
+    ruleset: &'static str,
+    time_control: String,
+    black_player: String,
+    white_player: String,
+    rated: bool,
+    hints: bool,
+    created: u64,
+    frame: String,
+    result: String,
+    moves: Vec<MoveData>
+}
+
+pub async fn get_record(
+    Path(id): Path<usize>,
+    State(state): State<AppState>
+) -> Result<response::Html<String>, StatusCode> {
+    let record = state.storage.load(id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let game = record.game().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let settings = &record.settings;
+    let (width, height) = (settings.board_size, settings.height());
+    let vertex = |position: usize| gtp::index_to_vertex(position, width as usize, height as usize);
+    let moves = record.moves.iter().enumerate().map(|(number, entry)| Ok(MoveData {
+        number: number + 1,
+        stone: entry.stone.into(),
+        action: match &entry.action {
+            Action::Handicap(positions) => positions.iter()
+                .map(|&x| vertex(x))
+                .collect::<Result<Vec<String>, CoordError>>()?
+                .join(" "),
+            Action::Play(position) => vertex(*position)?,
+            Action::Pass => "Pass".to_string(),
+            Action::Resign => "Resign".to_string()
+        }
+    })).collect::<Result<Vec<MoveData>, CoordError>>()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    //Final position in the packet format used by the board
+    let frame = serde_json::json!({
+        "width": game.width,
+        "height": game.height,
+        "board": game.board.iter().map(|x| match x {
+            Stone::Empty => 0,
+            Stone::Black => 1,
+            Stone::White => 2
+        }).collect::<Vec<u8>>(),
+        "moves": vec![false; game.board.len()],
+        "last_move": game.last_move(),
+        "dead": game.dead
+    });
+    let data = RecordTemplateData {
+        id,
+        board_size: width,
+        board_height: height,
+        komi: settings.komi,
+        handicap: settings.handicap,
+        placement: settings.placement,
+        ruleset: settings.ruleset.into(),
+        time_control: settings.time_control.to_string(),
+        black_player: settings.black_player.clone(),
+        white_player: settings.white_player.clone(),
+        rated: settings.rated,
+        hints: state.analyzer.is_some() && width == height && record.result.is_some(),
+        created: record.created.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        frame: frame.to_string(),
+        result: serde_json::to_string(&record.result).unwrap(),
+        moves
+    };
+    let body = state.templates.render("record", &data).unwrap();
+    Ok(response::Html(body))
+}
+
+//Ask the analysis engine about a position, at most once per interval for each game
+//GTP engines only play on square boards
+async fn analyze(state: &AppState, id: usize, game: &Game) -> Result<Analysis, AnalysisError> {
+    let analyzer = state.analyzer.as_ref().ok_or(AnalysisError::Disabled)?;
+    if game.width != game.height {
+        return Err(AnalysisError::Disabled)
+    }
+    if !state.hint_limiter.check(id) {
+        return Err(AnalysisError::RateLimited)
+    }
+    Ok(analyzer.analyze(game).await?)
+}
+
+//Position of a running game, if the session allows hints
+async fn hint_game(session: &mpsc::UnboundedSender<Message>) -> Result<Game, AnalysisError> {
+    let (sender, receiver) = oneshot::channel();
+    if session.send(Message::Hint(sender)).is_err() {
+        return Err(AnalysisError::Disabled)
+    }
+    receiver.await.map_err(|_| AnalysisError::Disabled)?
+}
+
+//Hint for a player of a running game
+async fn session_hint(
+    state: &AppState,
+    id: usize,
+    session: &mpsc::UnboundedSender<Message>
+) -> Result<Analysis, AnalysisError> {
+    let game = hint_game(session).await?;
+    analyze(state, id, &game).await
+}
+
+fn hint_error(error: AnalysisError) -> (StatusCode, String) {
+    let status = match error {
+        AnalysisError::Disabled | AnalysisError::Players => StatusCode::FORBIDDEN,
+        AnalysisError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        AnalysisError::Engine(_) => StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, error.to_string())
+}
+
+#[derive(Deserialize)]
+pub struct HintQuery {
+    //Number of moves to replay in stored games (empty for all)
+    #[serde(rename = "move")]
+    limit: Option<usize>
+}
+
+//Suggested move for a finished game, after some moves
+//Players of running games ask over their WebSocket
+pub async fn get_hint(
+    Path(id): Path<usize>,
+    Query(query): Query<HintQuery>,
+    State(state): State<AppState>
+) -> Result<Json<Analysis>, (StatusCode, String)> {
+    let session = {
+        let sessions = state.sessions.lock().unwrap();
+        sessions.get(&id).cloned()
+    };
+    if let Some((session, _)) = session {
+        let game = hint_game(&session).await.map_err(hint_error)?;
+        if game.turn != Turn::End {
+            return Err(hint_error(AnalysisError::Players))
+        }
+        return analyze(&state, id, &game).await.map(Json).map_err(hint_error)
+    }
+    let not_found = || (StatusCode::NOT_FOUND, "Game not found".to_string());
+    let mut record = state.storage.load(id)
+        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
+        .ok_or_else(not_found)?;
+    //Unfinished games stay closed
+    if record.result.is_none() {
+        return Err(hint_error(AnalysisError::Players))
+    }
+    if let Some(limit) = query.limit.filter(|&limit| limit < record.moves.len()) {
+        record.moves.truncate(limit);
+        record.result = None;
+        record.dead.clear();
+    }
+    let game = record.game().map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    analyze(&state, id, &game).await.map(Json).map_err(hint_error)
+}
+
 pub async fn spectate(
     Path(id): Path<usize>,
     State(state): State<AppState>
 ) -> Result<
-    sse::Sse<impl Stream<Item = Result<sse::Event, Box<BroadcastStreamRecvError>>>>,
+    sse::Sse<impl Stream<Item = Result<sse::Event, Infallible>>>,
     StatusCode
 > {
     let session = {
         let sessions = state.sessions.lock().unwrap();
-        let session = sessions.get(&id);
-        if let Some(session) = session {
-            Some(session.clone())
-        } else {
-            None
-        }
+        sessions.get(&id).cloned()
     };
-    if let Some((session, broadcast)) = session {
-        let receiver = broadcast.subscribe();
+    if let Some((session, _)) = session {
+        let (receiver, history) = subscribe(&session).await.ok_or(StatusCode::NOT_FOUND)?;
         let message = Message::Ping;
         if session.send(message).is_err() {
             return Err(StatusCode::NOT_FOUND);
         }
-        let stream = BroadcastStream::new(receiver).map(
-            |item| match item {
-                Ok(item) => {
-                    let event = sse::Event::default().json_data(item).unwrap();
-                    Ok(event)
-                },
-                Err(item) => Err(Box::new(item))
-            }
-        );
+        //Earlier chat first, then events for spectators
+        let history = history.into_iter().map(Event::Chat);
+        let stream = stream::iter(history).chain(events(session, receiver))
+            .filter(|event| future::ready(event.public()))
+            .map(|event| Ok(sse::Event::default().json_data(event).unwrap()));
         Ok(sse::Sse::new(stream))
     } else {
         Err(StatusCode::NOT_FOUND)
@@ -257,8 +851,95 @@ pub async fn spectate(
     }
     Pass: {action: 'pass'}
     Resign: {action: 'resign'}
+    Mark dead/alive: {
+        action: 'mark',
+        position: 0
+    }
+    Accept score: {action: 'accept'}
+    Agree to hints: {
+        action: 'allow_hints',
+        allow: true
+    }
+    Ask for a hint: {action: 'hint'}
+    Ask to take back the last move: {action: 'undo'}
+    Answer a takeback request: {
+        action: 'undo_reply',
+        accept: true
+    }
+    Chat, spectators only write to spectators: {
+        action: 'chat',
+        channel: 'players' | 'spectators',
+        text: 'Hello'
+    }
 */
 
+//Listen to a session, with the chat history up to that point
+async fn subscribe(
+    session: &mpsc::UnboundedSender<Message>
+) -> Option<(broadcast::Receiver<Event>, Vec<session::Chat>)> {
+    let (sender, receiver) = oneshot::channel();
+    session.send(Message::Subscribe(sender)).ok()?;
+    receiver.await.ok()
+}
+
+//Session events until it stops
+//Listeners that fall behind skip what they missed and get the current packet instead
+fn events(session: mpsc::UnboundedSender<Message>, receiver: broadcast::Receiver<Event>) -> impl Stream<Item = Event> {
+    BroadcastStream::new(receiver).filter_map(move |event| {
+        let session = session.clone();
+        async move {
+            match event {
+                Ok(event) => Some(event),
+                Err(BroadcastStreamRecvError::Lagged(_)) => {
+                    let (sender, receiver) = oneshot::channel();
+                    session.send(Message::Packet(sender)).ok()?;
+                    receiver.await.ok().map(Event::Packet)
+                }
+            }
+        }
+    })
+}
+
+//Chat messages allowed for each connection within the window
+const CHAT_RATE: usize = 5;
+const CHAT_WINDOW: Duration = Duration::from_secs(10);
+
+struct ChatLimiter {
+    sent: VecDeque<Instant>
+}
+
+impl ChatLimiter {
+    fn new() -> ChatLimiter {
+        ChatLimiter {sent: VecDeque::with_capacity(CHAT_RATE)}
+    }
+    //Record a message sent at `now`, returns false if the connection sent too many
+    fn check(&mut self, now: Instant) -> bool {
+        while self.sent.front().is_some_and(|time| now.duration_since(*time) >= CHAT_WINDOW) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= CHAT_RATE {
+            return false
+        }
+        self.sent.push_back(now);
+        true
+    }
+    //Reason to refuse a chat message, if any
+    fn refuse(&mut self, text: &str, now: Instant) -> Option<String> {
+        if text.chars().count() > session::CHAT_LENGTH {
+            Some(format!("Messages are limited to {} characters", session::CHAT_LENGTH))
+        } else if !self.check(now) {
+            Some("Too many messages, wait a moment".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+fn hint_request(message: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(message)
+        .is_ok_and(|value| value.get("action").and_then(|action| action.as_str()) == Some("hint"))
+}
+
 fn parse_message(
     stone: Stone,
     message: &str
@@ -281,6 +962,29 @@ fn parse_message(
             },
             "pass" => Some(session::Message::Pass(stone)),
             "resign" => Some(session::Message::Resign(stone)),
+            "mark" => {
+                let position = value.get("position")?.as_u64()? as usize;
+                Some(session::Message::Mark(stone, position))
+            },
+            "accept" => Some(session::Message::Accept(stone)),
+            "allow_hints" => {
+                let allow = value.get("allow")?.as_bool()?;
+                Some(session::Message::AllowHints(stone, allow))
+            },
+            "undo" => Some(session::Message::Undo(stone)),
+            "undo_reply" => {
+                let accept = value.get("accept")?.as_bool()?;
+                Some(session::Message::UndoReply(stone, accept))
+            },
+            "chat" => {
+                let channel = match value.get("channel").and_then(|channel| channel.as_str()) {
+                    Some("players") => Channel::Players,
+                    Some("spectators") | None => Channel::Spectators,
+                    Some(_) => return None
+                };
+                let text = value.get("text")?.as_str()?.trim();
+                Some(session::Message::Chat(stone, channel, text.to_string()))
+            },
             _ => None
         }
     } else {
@@ -288,6 +992,7 @@ fn parse_message(
     }
 }
 
+//Players, or spectators who only chat, their events come through SSE
 pub async fn connection(
     socket: ws::WebSocketUpgrade,
     Path((game, stone)): Path<(usize, String)>,
@@ -297,25 +1002,44 @@ pub async fn connection(
         let (mut socket_sender, mut socket_receiver) = socket.split();
         let session = {
             let sessions = state.sessions.lock().unwrap();
-            let session = sessions.get(&game);
-            if let Some(session) = session {
-                Some(session.clone())
-            } else {
-                None
-            }
+            sessions.get(&game).map(|(session, _)| session.clone())
         };
-        if let Some((session, broadcast)) = session {
+        if let Some(session) = session {
             let stone = match stone.as_str() {
                 "black" => Stone::Black,
                 "white" => Stone::White,
+                "spectator" => Stone::Empty,
                 _ => return
             };
-            //Broadcast listener
-            let mut broadcast_receiver = broadcast.subscribe();
+            let player = stone != Stone::Empty;
+            //Broadcast listener, and events for this connection only
+            let (broadcast_receiver, history) = if player {
+                match subscribe(&session).await {
+                    Some((receiver, history)) => (Some(receiver), history),
+                    None => return
+                }
+            } else {
+                (None, Vec::new())
+            };
+            let (direct, mut direct_receiver) = mpsc::unbounded_channel::<Event>();
+            //Chat history first, spectators only get direct events
+            let history = history.into_iter().map(Event::Chat);
+            let broadcast = match broadcast_receiver {
+                Some(receiver) => events(session.clone(), receiver).boxed(),
+                None => stream::pending().boxed()
+            };
             let handle = tokio::spawn(async move {
-                while let Ok(packet) = broadcast_receiver.recv().await {
+                let mut events = stream::iter(history).chain(broadcast);
+                loop {
+                    let event = tokio::select! {
+                        event = events.next() => match event {
+                            Some(event) => event,
+                            None => break
+                        },
+                        Some(event) = direct_receiver.recv() => event
+                    };
                     let message = ws::Message::Text(
-                        serde_json::to_value(packet).unwrap().to_string()
+                        serde_json::to_value(event).unwrap().to_string()
                     );
                     if socket_sender.send(message).await.is_err() {
                         break;
@@ -324,23 +1048,136 @@ pub async fn connection(
             });
             //WebSocket listener
             //Attempt to join session
-            let (once_sender, once_receiver) = oneshot::channel();
-            let message = Message::Join(stone, once_sender);
-            if session.send(message).is_ok() {
-                if let Ok(true) = once_receiver.await {
-                    //Listen for messages
-                    while let Some(Ok(ws::Message::Text(message))) = socket_receiver.next().await {
-                        if let Some(message) = parse_message(stone, &message) {
-                            if session.send(message).is_err() {
-                                break
+            let joined = if player {
+                let (once_sender, once_receiver) = oneshot::channel();
+                let message = Message::Join(stone, once_sender);
+                session.send(message).is_ok() && once_receiver.await == Ok(true)
+            } else {
+                true
+            };
+            if joined {
+                let mut limiter = ChatLimiter::new();
+                //Listen for messages
+                while let Some(Ok(ws::Message::Text(message))) = socket_receiver.next().await {
+                    if player && hint_request(&message) {
+                        let (state, session, direct) = (state.clone(), session.clone(), direct.clone());
+                        tokio::spawn(async move {
+                            let event = match session_hint(&state, game, &session).await {
+                                Ok(analysis) => Event::Hint(analysis),
+                                Err(error) => Event::HintError {message: error.to_string()}
+                            };
+                            let _ = direct.send(event);
+                        });
+                    } else if let Some(message) = parse_message(stone, &message) {
+                        if let Message::Chat(_, _, text) = &message {
+                            if let Some(message) = limiter.refuse(text, Instant::now()) {
+                                let _ = direct.send(Event::ChatError {message});
+                                continue
                             }
+                        } else if !player {
+                            continue
+                        }
+                        if session.send(message).is_err() {
+                            break
                         }
                     }
                 }
             }
             //Leave session 
-            let _ = session.send(Message::Leave(stone));
+            if player {
+                let _ = session.send(Message::Leave(stone));
+            }
             handle.abort();
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_players() {
+        let config = Config::default();
+        let gnugo = Some("gnugo".to_string());
+        assert_eq!(players(&config, "bot", "human", 19, 19), Ok([gnugo.clone(), None]));
+        assert_eq!(players(&config, "human", "gnugo", 9, 9), Ok([None, gnugo]));
+        //The default engine only plays up to 19x19
+        assert_eq!(players(&config, "bot", "human", 25, 25), Err("Board size not supported by engine"));
+        assert_eq!(players(&config, "human", "gnugo", 25, 25), Err("Board size not supported by engine"));
+        assert_eq!(players(&config, "bot", "human", 9, 13), Err("Engines only play on square boards"));
+        assert_eq!(players(&config, "katago", "human", 9, 9), Err("Unknown engine"));
+    }
+    #[test]
+    fn test_empty_as_zero() {
+        use serde::de::{IntoDeserializer, value::Error};
+        let parse = |text: &str| empty_as_zero::<_, u64>(IntoDeserializer::<Error>::into_deserializer(text));
+        assert_eq!(parse("").unwrap(), 0);
+        assert_eq!(parse(" 3 ").unwrap(), 3);
+        assert!(parse("three").is_err());
+    }
+    #[test]
+    fn test_chat_limiter() {
+        let mut limiter = ChatLimiter::new();
+        let start = Instant::now();
+        for i in 0..CHAT_RATE as u64 {
+            assert!(limiter.check(start + Duration::from_secs(i)));
+        }
+        assert!(!limiter.check(start + Duration::from_secs(9)));
+        //The first message leaves the window after ten seconds
+        assert!(limiter.check(start + CHAT_WINDOW));
+        assert!(!limiter.check(start + CHAT_WINDOW));
+        assert!(limiter.check(start + CHAT_WINDOW * 2));
+    }
+    #[test]
+    fn test_chat_refused() {
+        let mut limiter = ChatLimiter::new();
+        let now = Instant::now();
+        //Characters are counted, not bytes
+        let long = "碁".repeat(session::CHAT_LENGTH + 1);
+        assert!(limiter.refuse(&long, now).is_some());
+        assert!(limiter.refuse(&long[3..], now).is_none());
+        //Refused messages don't count towards the rate
+        for _ in 1..CHAT_RATE {
+            assert!(limiter.refuse("Hi", now).is_none());
+        }
+        assert!(limiter.refuse("Hi", now).is_some());
+    }
+    #[tokio::test]
+    async fn test_lagged_events() {
+        let (session, receiver) = mpsc::unbounded_channel();
+        let (broadcast, _) = broadcast::channel(32);
+        tokio::spawn(session::session(
+            1,
+            Arc::new(Mutex::new(session::Sessions::new())),
+            session.clone(),
+            receiver,
+            broadcast.clone(),
+            Game::new(9, 6.5, 1, Placement::Free, Ruleset::Chinese).unwrap(),
+            [None, None],
+            [None, None],
+            Clocks::new(TimeControl::Absolute{main: 600}),
+            Duration::ZERO,
+            false,
+            false,
+            Arc::new(Config::default()),
+            Arc::new(Storage::memory().unwrap()),
+            Arc::new(Pool::new(1, Duration::ZERO))
+        ));
+        let mut events = Box::pin(events(session.clone(), broadcast.subscribe()));
+        drop(broadcast);
+        for i in 0..40 {
+            session.send(Message::Chat(Stone::Black, Channel::Spectators, i.to_string())).unwrap();
+        }
+        let (sender, receiver) = oneshot::channel();
+        session.send(Message::Packet(sender)).unwrap();
+        receiver.await.unwrap();
+        //The 8 messages that didn't fit are replaced by the current packet
+        assert!(matches!(events.next().await, Some(Event::Packet(_))));
+        for i in 8..40 {
+            assert!(matches!(events.next().await, Some(Event::Chat(chat)) if chat.text == i.to_string()));
+        }
+        //Later events still arrive
+        session.send(Message::Ping).unwrap();
+        assert!(matches!(events.next().await, Some(Event::Packet(_))));
+    }
+}
@@ -1,6 +1,14 @@
 pub mod model;
+pub mod board;
+pub mod coord;
 pub mod handlers;
 pub mod session;
 pub mod timer;
 pub mod engine;
+pub mod pool;
+pub mod gtp;
+pub mod analysis;
+pub mod sgf;
+pub mod storage;
+pub mod config;
 //pub mod packet;
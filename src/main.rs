@@ -1,58 +1,136 @@
 use axum::{
     Router,
-    response,
     routing
 };
-use casual_go::handlers;
+use casual_go::{
+    handlers,
+    analysis::{Analyzer, RateLimiter},
+    pool::Pool,
+    storage::Storage,
+    config::{Args, Config}
+};
+use clap::Parser;
 use handlebars::Handlebars;
+use tokio::{signal, sync::oneshot, time::Instant};
+use tower_http::services::ServeDir;
 use std::{
-    env,
+    process,
     collections::HashMap,
-    sync::{Arc, Mutex},
-    net::{Ipv6Addr, SocketAddr, IpAddr}
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    time::Duration,
+    net::SocketAddr
 };
 
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
-    let port: u16 = if let Some(port) = args.get(1) {
-        port.parse().unwrap()
-    } else {
-        80
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1)
+        }
     };
-    let engine: String = if let Some(engine) = args.get(2) {
-        engine.into()
-    } else {
-        "gnugo".into()
+    let storage = match Storage::open(&config.server.database) {
+        Ok(storage) => storage,
+        Err(error) => {
+            eprintln!("Cannot open database {}: {}", config.server.database.display(), error);
+            process::exit(1)
+        }
     };
     //Templates
     let mut templates = Handlebars::new();
+    if templates.register_template_string("index", include_str!("../templates/index.hbs")).is_err() {
+        return
+    }
     if templates.register_template_string("lobby", include_str!("../templates/lobby.hbs")).is_err() {
         return
     }
     if templates.register_template_string("game", include_str!("../templates/game.hbs")).is_err() {
         return
     }
+    if templates.register_template_string("record", include_str!("../templates/record.hbs")).is_err() {
+        return
+    }
     //App
+    let socket = SocketAddr::new(config.server.address, config.server.port);
+    let drain = Duration::from_secs(config.server.drain);
+    let static_dir = config.server.static_dir.clone();
+    let pool = Arc::new(Pool::new(config.limits.max_engines, config.limits.engine_wait()));
+    let analyzer = config.analysis_engine.as_ref().map(|id| {
+        Arc::new(Analyzer::new(config.engines[id].clone(), config.limits.analysis_engines, pool.clone()))
+    });
+    let hint_limiter = Arc::new(RateLimiter::new(config.limits.hint_interval()));
     let state = handlers::AppState {
         templates,
         sessions: Arc::new(Mutex::new(HashMap::new())),
-        engine
+        config: Arc::new(config),
+        storage: Arc::new(storage),
+        shutting_down: Arc::new(AtomicBool::new(false)),
+        pool,
+        analyzer,
+        hint_limiter
     };
-    let app = Router::new()
-        .route("/", routing::get(|| async {
-            response::Html(include_str!("../templates/index.html").to_string())
-        })).route("/create", routing::post(handlers::create_session))
+    let mut app = Router::new()
+        .route("/", routing::get(handlers::index))
+        .route("/create", routing::post(handlers::create_session))
+        .route("/create/sgf", routing::post(handlers::import_session))
         .route("/play/:game", routing::get(handlers::get_session))
         .route("/play/:game/:side", routing::get(handlers::join_session))
+        .route("/sgf/:game", routing::get(handlers::export_session))
+        .route("/games/:game", routing::get(handlers::get_record))
+        .route("/hint/:game", routing::get(handlers::get_hint))
         .route("/ws/:game/:side", routing::get(handlers::connection))
-        .route("/sse/:game", routing::get(handlers::spectate))
-        .with_state(state);
-    let socket = SocketAddr::new(
-        IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
-        port
-    );
-    axum::Server::bind(&socket)
+        .route("/sse/:game", routing::get(handlers::spectate));
+    if let Some(static_dir) = static_dir {
+        app = app.nest_service("/static", ServeDir::new(static_dir));
+    }
+    let app = app.with_state(state.clone());
+    let listener = match axum::Server::try_bind(&socket) {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("Cannot listen on {}: {}", socket, error);
+            process::exit(1)
+        }
+    };
+    //Metrics are only served on their own listener
+    if let Some(metrics_address) = state.config.server.metrics_address {
+        let metrics_listener = match axum::Server::try_bind(&metrics_address) {
+            Ok(listener) => listener,
+            Err(error) => {
+                eprintln!("Cannot listen for metrics on {}: {}", metrics_address, error);
+                process::exit(1)
+            }
+        };
+        let metrics = Router::new()
+            .route("/metrics", routing::get(handlers::metrics))
+            .with_state(state.clone());
+        tokio::spawn(metrics_listener.serve(metrics.into_make_service()));
+    }
+    //Sessions interrupted by the last shutdown
+    handlers::resume_sessions(&state);
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = listener
         .serve(app.into_make_service())
-        .await.unwrap();
+        .with_graceful_shutdown(async {
+            let _ = stopped.await;
+        });
+    let server = tokio::spawn(server);
+    shutdown_signal().await;
+    let deadline = Instant::now() + drain;
+    state.shutting_down.store(true, Ordering::Relaxed);
+    //Stop the listener first, so sessions are saved after the last request reaches them
+    let _ = stop.send(());
+    handlers::suspend_sessions(&state, drain).await;
+    //Connections close once their session has stopped
+    let _ = tokio::time::timeout_at(deadline, server).await;
+}
+
+async fn shutdown_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+        .expect("Error installing signal handler");
+    tokio::select! {
+        _ = interrupt => (),
+        _ = terminate.recv() => ()
+    }
 }
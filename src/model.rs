@@ -1,20 +1,47 @@
 use std::{
     fmt,
     error,
-    collections::HashSet
+    collections::HashSet,
+    time::SystemTime
 };
 use serde::{Serialize, Deserialize};
+use crate::{
+    timer::TimeControl,
+    board::{self, Board}
+};
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Settings {
     pub board_size: u32,
-    pub komi: u32,
+    //Height of rectangular boards, whose width is board_size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub board_height: Option<u32>,
+    pub komi: f32,
     pub handicap: u32,
-    pub fixed_time: u32,
-    pub added_time: u32
+    pub placement: Placement,
+    pub ruleset: Ruleset,
+    pub time_control: TimeControl,
+    //Display names
+    #[serde(default)]
+    pub black_player: String,
+    #[serde(default)]
+    pub white_player: String,
+    //Rated games get no engine hints while in progress
+    #[serde(default)]
+    pub rated: bool,
+    //Players may ask to take back moves
+    #[serde(default)]
+    pub takebacks: bool
+}
+
+impl Settings {
+    pub fn height(&self) -> u32 {
+        self.board_height.unwrap_or(self.board_size)
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Stone {
     Black,
     White,
@@ -31,11 +58,22 @@ impl From<Stone> for &str {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+impl Stone {
+    pub fn opponent(self) -> Stone {
+        match self {
+            Stone::Black => Stone::White,
+            Stone::White => Stone::Black,
+            Stone::Empty => Stone::Empty
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Turn {
     Handicap,
     Black,
     White,
+    Scoring,
     End
 }
 
@@ -45,14 +83,148 @@ impl From<Turn> for &str {
             Turn::Handicap => "handicap",
             Turn::Black => "black",
             Turn::White => "white",
+            Turn::Scoring => "scoring",
             Turn::End => "end"
         }
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scoring {
+    //Stones and surrounded points
+    Area,
+    //Surrounded points and prisoners
+    Territory
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "lowercase")]
+pub enum GameResult {
+    Score {winner: Stone, margin: f32},
+    Draw,
+    Resignation {winner: Stone},
+    Timeout {winner: Stone},
+    //Player abandoned the game
+    Forfeit {winner: Stone},
+    //Game expired, or engines played too long, before it finished
+    Aborted,
+    //Engine playing this side stopped working
+    #[serde(rename = "engine-failure")]
+    EngineFailure {stone: Stone}
+}
+
+impl fmt::Display for GameResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let color = |stone: &Stone| match stone {
+            Stone::Black => "B",
+            _ => "W"
+        };
+        match self {
+            Self::Score {winner, margin} => write!(f, "{}+{}", color(winner), margin),
+            Self::Draw => write!(f, "Draw"),
+            Self::Resignation {winner} => write!(f, "{}+R", color(winner)),
+            Self::Timeout {winner} => write!(f, "{}+T", color(winner)),
+            Self::Forfeit {winner} => write!(f, "{}+F", color(winner)),
+            Self::Aborted | Self::EngineFailure {..} => write!(f, "Void")
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Placement {
+    //Black chooses where handicap stones go
+    Free,
+    //Handicap stones go on the star points
+    Fixed
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Ko {
+    //No immediate recapture
+    Simple,
+    //No repetition of a board position
+    Positional,
+    //No repetition of a board position with the same player to move
+    Situational
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ruleset {
+    Chinese,
+    Japanese,
+    Aga,
+    NewZealand,
+    TrompTaylor
+}
+
+impl From<Ruleset> for &str {
+    fn from(value: Ruleset) -> Self {
+        match value {
+            Ruleset::Chinese => "Chinese",
+            Ruleset::Japanese => "Japanese",
+            Ruleset::Aga => "AGA",
+            Ruleset::NewZealand => "New Zealand",
+            Ruleset::TrompTaylor => "Tromp-Taylor"
+        }
+    }
+}
+
+impl Ruleset {
+    pub fn suicide(self) -> bool {
+        matches!(self, Ruleset::NewZealand | Ruleset::TrompTaylor)
+    }
+    pub fn ko(self) -> Ko {
+        match self {
+            Ruleset::Japanese => Ko::Simple,
+            Ruleset::Chinese | Ruleset::TrompTaylor => Ko::Positional,
+            Ruleset::Aga | Ruleset::NewZealand => Ko::Situational
+        }
+    }
+    pub fn scoring(self) -> Scoring {
+        match self {
+            Ruleset::Japanese => Scoring::Territory,
+            _ => Scoring::Area
+        }
+    }
+    pub fn pass_stones(self) -> bool {
+        self == Ruleset::Aga
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Handicap(Vec<usize>),
+    Play(usize),
+    Pass,
+    Resign
+}
+
+#[derive(Clone, Debug)]
+pub struct Move {
+    pub action: Action,
+    pub stone: Stone,
+    pub captures: Vec<usize>,
+    pub time: SystemTime
+}
+
+impl Move {
+    fn new(action: Action, stone: Stone, captures: Vec<usize>) -> Move {
+        Move {
+            action,
+            stone,
+            captures,
+            time: SystemTime::now()
+        }
+    }
+}
+
 fn neighbors(n: usize, board: &[Stone], pos: usize) -> Vec<usize> {
     let mut result = Vec::<usize>::new();
-    if pos >= 1 && pos % n > 0 {
+    if pos >= 1 && !pos.is_multiple_of(n) {
         result.push(pos - 1);
     }
     if pos + 1 < board.len() && pos % n < n - 1 {
@@ -86,50 +258,43 @@ fn connected_group(n: usize, board: &[Stone], pos: usize) -> Vec<usize> {
     result
 }
 
-fn liberty(n: usize, board: &[Stone], group: &[usize]) -> bool {
-    for &pos in group {
-        if board[pos] == Stone::Empty {
-            return true;
-        }
-        for neighbor in neighbors(n, board, pos) {
-            if board[neighbor] == Stone::Empty {
-                return true;
-            }
-        }
+//Only square boards have fixed handicap points
+//In the order of GTP fixed_handicap: D4, Q16, D16, Q4, D10, Q10, K4, K16, K10 on 19x19
+fn star_points(width: usize, height: usize, count: u32) -> Option<Vec<usize>> {
+    let n = width;
+    if n != height || n < 7 || (count > 4 && n.is_multiple_of(2)) {
+        return None
     }
-    false
-}
-
-fn place_stone(n: usize, board: &mut [Stone], stone: Stone, pos: usize) -> u32 {
-    board[pos] = stone;
-    let mut captures = 0;
-    //Capture
-    for neighbor in neighbors(n, &board, pos) {
-        if board[neighbor] != stone && board[neighbor] != Stone::Empty {
-            let group = connected_group(n, &board, neighbor);
-            if !liberty(n, &board, &group) {
-                captures += group.len() as u32;
-                for pos in group {
-                    board[pos] = Stone::Empty;
-                }
-            }
-        }
+    //Distance from the edge
+    let edge = if n >= 13 {3} else {2};
+    let (near, far, middle) = (edge, n - 1 - edge, n / 2);
+    let point = |x: usize, y: usize| n * y + x;
+    let mut points = vec![point(near, far), point(far, near)];
+    if count >= 3 {
+        points.push(point(near, near));
     }
-    //Self-capture
-    let group = connected_group(n, &board, pos);
-    if !liberty(n, &board, &group) {
-        captures += group.len() as u32;
-        for pos in group {
-            board[pos] = Stone::Empty;
-        }
+    if count >= 4 {
+        points.push(point(far, far));
+    }
+    if count >= 6 {
+        points.push(point(near, middle));
+        points.push(point(far, middle));
+    }
+    if count >= 8 {
+        points.push(point(middle, far));
+        points.push(point(middle, near));
     }
-    captures
+    if count % 2 == 1 && count >= 5 {
+        points.push(point(middle, middle));
+    }
+    Some(points)
 }
 
 pub enum GameError {
     Creation,
     Handicap,
-    Play
+    Play,
+    Undo
 }
 
 impl fmt::Display for GameError {
@@ -138,6 +303,7 @@ impl fmt::Display for GameError {
             Self::Creation => writeln!(f, "Creation error"),
             Self::Handicap => writeln!(f, "Handicap error"),
             Self::Play => writeln!(f, "Play error"),
+            Self::Undo => writeln!(f, "Undo error"),
         }
     }
 }
@@ -148,6 +314,7 @@ impl fmt::Debug for GameError {
             Self::Creation => writeln!(f, "Creation error"),
             Self::Handicap => writeln!(f, "Handicap error"),
             Self::Play => writeln!(f, "Play error"),
+            Self::Undo => writeln!(f, "Undo error"),
         }
     }
 }
@@ -157,32 +324,85 @@ impl error::Error for GameError {}
 #[derive(Clone)]
 pub struct Game {
     //Settings
-    pub board_size: usize,
-    pub komi: u32,
+    pub width: usize,
+    pub height: usize,
+    pub komi: f32,
     pub handicap: u32,
+    pub placement: Placement,
     //Game state
     pub board: Vec<Stone>,
     pub history: Vec<Vec<Stone>>,
+    position: Board,
+    //Zobrist hash of each position in the history
+    hashes: Vec<u64>,
+    //Seen positions, and positions with the player who made them, for superko
+    positions: HashSet<u64>,
+    situations: HashSet<u64>,
+    pub moves: Vec<Move>,
     pub valid_moves: Vec<bool>,
     pub turn: Turn,
     pub passes: u32,
-    //Score
-    pub black_score: u32,
-    pub white_score: u32
+    pub ruleset: Ruleset,
+    //Scoring
+    pub captures: [u32; 2],
+    pub dead: Vec<bool>,
+    pub accepted: [bool; 2],
+    pub black_score: f32,
+    pub white_score: f32,
+    pub result: Option<GameResult>,
+    //Result suggested by the scoring engine
+    pub estimate: Option<GameResult>
 }
 
-//TODO: Fixed handicap placement
+//Largest board GTP allows
+pub const MAX_BOARD_SIZE: usize = 25;
+
 impl Game {
-    pub fn new(board_size: usize, komi: u32, handicap: u32) -> Result<Game, GameError> {
-        if board_size >= 5 && board_size <= 19 && handicap > 0 && handicap <= 9 {
-            let tile_count = board_size * board_size;
-            let board = vec![Stone::Empty; tile_count];
-            Ok(Game {
-                board_size,
+    //Game on a square board
+    pub fn new(
+        board_size: usize,
+        komi: f32,
+        handicap: u32,
+        placement: Placement,
+        ruleset: Ruleset
+    ) -> Result<Game, GameError> {
+        Game::rectangular(board_size, board_size, komi, handicap, placement, ruleset)
+    }
+    pub fn rectangular(
+        width: usize,
+        height: usize,
+        komi: f32,
+        handicap: u32,
+        placement: Placement,
+        ruleset: Ruleset
+    ) -> Result<Game, GameError> {
+        //Sizes are checked before counting points, which could overflow
+        if !(2..=MAX_BOARD_SIZE).contains(&width) || !(2..=MAX_BOARD_SIZE).contains(&height) {
+            return Err(GameError::Handicap)
+        }
+        let tile_count = width * height;
+        //Komi is counted in half points, and can't be worth more than the board
+        if !komi.is_finite() || (komi * 2.0).fract() != 0.0 || komi.abs() > tile_count as f32 {
+            return Err(GameError::Creation)
+        }
+        //Handicap stones leave at least one point free
+        if (1..=9).contains(&handicap) && (handicap as usize) < tile_count {
+            let position = Board::new(width, height);
+            let hash = position.hash();
+            let mut game = Game {
+                width,
+                height,
                 komi,
                 handicap,
-                board: board.clone(),
-                history: vec![board.clone()],
+                placement,
+                board: position.stones().to_vec(),
+                history: vec![position.stones().to_vec()],
+                position,
+                hashes: vec![hash],
+                positions: HashSet::from([hash]),
+                //The initial position has Black to move
+                situations: HashSet::from([hash ^ board::mover(Stone::White)]),
+                moves: Vec::new(),
                 valid_moves: vec![true; tile_count],
                 turn: if handicap == 1 {
                     Turn::Black
@@ -190,9 +410,20 @@ impl Game {
                     Turn::Handicap
                 },
                 passes: 0,
-                black_score: 0,
-                white_score: 0
-            })
+                ruleset,
+                captures: [0, 0],
+                dead: vec![false; tile_count],
+                accepted: [false, false],
+                black_score: 0.0,
+                white_score: komi,
+                result: None,
+                estimate: None
+            };
+            if placement == Placement::Fixed && handicap > 1 {
+                let points = star_points(width, height, handicap).ok_or(GameError::Handicap)?;
+                game.play_handicap(Stone::Black, &points)?;
+            }
+            Ok(game)
         } else {
             Err(GameError::Handicap)
         }
@@ -204,9 +435,13 @@ impl Game {
                 .collect();
             if set.len() == positions.len() && positions.len() <= self.handicap as usize {
                 for &i in positions {
-                    self.board[i] = stone;
+                    self.position.place(stone, i);
                 }
+                self.board = self.position.stones().to_vec();
+                self.record(stone);
+                self.moves.push(Move::new(Action::Handicap(positions.to_vec()), stone, Vec::new()));
                 self.turn = Turn::White;
+                self.valid_moves = self.next_moves(Stone::White)?;
                 Ok(())
             } else {
                 Err(GameError::Handicap)
@@ -215,22 +450,33 @@ impl Game {
             Err(GameError::Handicap)
         }
     }
+    //Remember the position after a move
+    fn record(&mut self, stone: Stone) {
+        let hash = self.position.hash();
+        self.history.push(self.board.clone());
+        self.hashes.push(hash);
+        self.positions.insert(hash);
+        self.situations.insert(hash ^ board::mover(stone));
+    }
+    fn repeats(&self, hash: u64, stone: Stone) -> bool {
+        match self.ruleset.ko() {
+            Ko::Simple => self.hashes.len() >= 2
+                && self.hashes[self.hashes.len() - 2] == hash,
+            Ko::Positional => self.positions.contains(&hash),
+            Ko::Situational => self.situations.contains(&(hash ^ board::mover(stone)))
+        }
+    }
     fn next_moves(&self, stone: Stone) -> Result<Vec<bool>, GameError> {
         if stone == Stone::Black || stone == Stone::White {
             let mut moves = vec![true; self.board.len()];
-            for pos in 0..self.board.len() {
+            for (pos, legal) in moves.iter_mut().enumerate() {
                 if self.board[pos] == Stone::Empty {
-                    let mut board = self.board.clone();
-                    let captures = place_stone(self.board_size, &mut board, stone, pos);
-                    if captures > 0 {
-                        for entry in &self.history {
-                            if board == *entry {
-                                moves[pos] = false;
-                            }
-                        }
-                    }
+                    //Only moves that remove stones can repeat a position
+                    let outcome = self.position.check(stone, pos);
+                    *legal = (!outcome.suicide || self.ruleset.suicide())
+                        && (!outcome.captures || !self.repeats(outcome.hash, stone));
                 } else {
-                    moves[pos] = false;
+                    *legal = false;
                 }
             }
             Ok(moves)
@@ -246,8 +492,16 @@ impl Game {
                 || (stone == Stone::White && self.turn == Turn::White)
             ) && self.valid_moves[pos] {
             //Place stone
-            place_stone(self.board_size, &mut self.board, stone, pos);
-            self.history.push(self.board.clone());
+            let captures = self.position.place(stone, pos);
+            self.board = self.position.stones().to_vec();
+            //Prisoners (self-captured stones go to the opponent)
+            let prisoners = captures.len() as u32;
+            match (stone, captures.contains(&pos)) {
+                (Stone::Black, false) | (Stone::White, true) => self.captures[0] += prisoners,
+                _ => self.captures[1] += prisoners
+            }
+            self.record(stone);
+            self.moves.push(Move::new(Action::Play(pos), stone, captures));
             //Advance turn
             self.turn = match self.turn {
                 Turn::Black => Turn::White,
@@ -273,8 +527,22 @@ impl Game {
         if (stone == Stone::Black && self.turn == Turn::Black)
             || (stone == Stone::White && self.turn == Turn::White) {
             self.passes += 1;
-            if self.passes == 2 {
-                self.turn = Turn::End;
+            //Passing hands a prisoner to the opponent
+            if self.ruleset.pass_stones() {
+                match stone {
+                    Stone::Black => self.captures[1] += 1,
+                    _ => self.captures[0] += 1
+                }
+            }
+            self.record(stone);
+            self.moves.push(Move::new(Action::Pass, stone, Vec::new()));
+            //With pass stones, White must pass last
+            if self.passes >= 2 && (!self.ruleset.pass_stones() || stone == Stone::White) {
+                self.turn = Turn::Scoring;
+                self.dead = vec![false; self.board.len()];
+                self.accepted = [false, false];
+                self.estimate = None;
+                self.valid_moves = vec![false; self.board.len()];
             } else {
                 match stone {
                     Stone::Black => {
@@ -293,33 +561,202 @@ impl Game {
             Err(GameError::Play)
         }
     }
+    //Moves to take back so the player's last move is undone, with at most the opponent's reply after it
+    fn undo_count(&self, stone: Stone) -> Option<usize> {
+        if !matches!(self.turn, Turn::Black | Turn::White) {
+            return None
+        }
+        self.moves.iter().rev().take(2)
+            .position(|entry| entry.stone == stone && matches!(entry.action, Action::Play(_) | Action::Pass))
+            .map(|index| index + 1)
+    }
+    pub fn can_undo(&self, stone: Stone) -> bool {
+        self.undo_count(stone).is_some()
+    }
+    //Take back the player's last move and any reply, returns the number of moves taken back
+    pub fn undo(&mut self, stone: Stone) -> Result<usize, GameError> {
+        let count = self.undo_count(stone).ok_or(GameError::Undo)?;
+        for _ in 0..count {
+            let entry = self.moves.pop().ok_or(GameError::Undo)?;
+            self.history.pop();
+            self.hashes.pop();
+            match entry.action {
+                Action::Play(pos) => {
+                    //Captured stones go back, self-captured ones are the player's own
+                    let suicide = entry.captures.contains(&pos);
+                    let captured = if suicide {entry.stone} else {entry.stone.opponent()};
+                    for &x in &entry.captures {
+                        self.board[x] = captured;
+                    }
+                    self.board[pos] = Stone::Empty;
+                    let prisoners = entry.captures.len() as u32;
+                    match (entry.stone, suicide) {
+                        (Stone::Black, false) | (Stone::White, true) => self.captures[0] -= prisoners,
+                        _ => self.captures[1] -= prisoners
+                    }
+                },
+                Action::Pass if self.ruleset.pass_stones() => match entry.stone {
+                    Stone::Black => self.captures[1] -= 1,
+                    _ => self.captures[0] -= 1
+                },
+                _ => ()
+            }
+        }
+        //Rebuild the position and the superko sets from what is left
+        self.position = Board::from_stones(self.width, self.height, &self.board);
+        self.positions = self.hashes.iter().copied().collect();
+        self.situations = self.hashes.iter().enumerate().map(|(i, &hash)| {
+            let mover = if i == 0 {Stone::White} else {self.moves[i - 1].stone};
+            hash ^ board::mover(mover)
+        }).collect();
+        self.passes = self.moves.iter().rev()
+            .take_while(|entry| entry.action == Action::Pass)
+            .count() as u32;
+        let next_stone = match self.moves.last() {
+            Some(entry) => entry.stone.opponent(),
+            None => Stone::Black
+        };
+        self.turn = if next_stone == Stone::Black {Turn::Black} else {Turn::White};
+        self.valid_moves = self.next_moves(next_stone)?;
+        self.dead = vec![false; self.board.len()];
+        self.accepted = [false, false];
+        self.estimate = None;
+        [self.black_score, self.white_score] = self.score();
+        Ok(count)
+    }
+    fn end(&mut self, result: GameResult) {
+        self.result = Some(result);
+        self.turn = Turn::End;
+    }
+    fn to_move(&self, stone: Stone) -> bool {
+        match stone {
+            Stone::Black => self.turn == Turn::Black || self.turn == Turn::Handicap,
+            Stone::White => self.turn == Turn::White,
+            Stone::Empty => false
+        }
+    }
     pub fn resign(&mut self, stone: Stone) -> Result<(), GameError> {
-        if stone == Stone::Black && self.turn == Turn::Black {
-            self.black_score = 0;
-            self.white_score = (self.board_size * self.board_size) as u32;
-            self.turn = Turn::End;
+        if self.to_move(stone) {
+            self.moves.push(Move::new(Action::Resign, stone, Vec::new()));
+            self.end(GameResult::Resignation {winner: stone.opponent()});
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn timeout(&mut self, stone: Stone) -> Result<(), GameError> {
+        if self.to_move(stone) {
+            self.end(GameResult::Timeout {winner: stone.opponent()});
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn forfeit(&mut self, stone: Stone) -> Result<(), GameError> {
+        if stone != Stone::Empty && matches!(self.turn, Turn::Handicap | Turn::Black | Turn::White) {
+            self.end(GameResult::Forfeit {winner: stone.opponent()});
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn engine_failure(&mut self, stone: Stone) -> Result<(), GameError> {
+        if stone != Stone::Empty && self.turn != Turn::End {
+            self.end(GameResult::EngineFailure {stone});
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn abort(&mut self) -> Result<(), GameError> {
+        if self.turn != Turn::End {
+            self.end(GameResult::Aborted);
+            Ok(())
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn mark(&mut self, stone: Stone, pos: usize) -> Result<(), GameError> {
+        if self.turn == Turn::Scoring
+            && stone != Stone::Empty
+            && pos < self.board.len()
+            && self.board[pos] != Stone::Empty {
+            //Toggle the whole group
+            let dead = !self.dead[pos];
+            for pos in connected_group(self.width, &self.board, pos) {
+                self.dead[pos] = dead;
+            }
+            self.accepted = [false, false];
+            [self.black_score, self.white_score] = self.score();
             Ok(())
-        } else if stone == Stone::White && self.turn == Turn::White {
-            self.white_score = 0;
-            self.black_score = (self.board_size * self.board_size) as u32;
-            self.turn = Turn::End;
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    //Mark the stones an engine considers dead, for the players to confirm
+    //Marks aren't touched once a player has marked or accepted, returns whether they were
+    pub fn suggest(&mut self, dead: &[usize], estimate: GameResult) -> Result<bool, GameError> {
+        if self.turn == Turn::Scoring && dead.iter().all(|&pos| pos < self.board.len()) {
+            self.estimate = Some(estimate);
+            if self.dead.contains(&true) || self.accepted.contains(&true) {
+                return Ok(false)
+            }
+            for &pos in dead {
+                if self.board[pos] != Stone::Empty {
+                    self.dead[pos] = true;
+                }
+            }
+            [self.black_score, self.white_score] = self.score();
+            Ok(true)
+        } else {
+            Err(GameError::Play)
+        }
+    }
+    pub fn accept(&mut self, stone: Stone) -> Result<(), GameError> {
+        if self.turn == Turn::Scoring {
+            match stone {
+                Stone::Black => self.accepted[0] = true,
+                Stone::White => self.accepted[1] = true,
+                Stone::Empty => return Err(GameError::Play)
+            }
+            if self.accepted[0] && self.accepted[1] {
+                [self.black_score, self.white_score] = self.score();
+                let margin = self.black_score - self.white_score;
+                self.end(if margin > 0.0 {
+                    GameResult::Score {winner: Stone::Black, margin}
+                } else if margin < 0.0 {
+                    GameResult::Score {winner: Stone::White, margin: -margin}
+                } else {
+                    GameResult::Draw
+                });
+            }
             Ok(())
         } else {
             Err(GameError::Play)
         }
     }
-    pub fn score(&self) -> [u32; 2] {
+    pub fn last_move(&self) -> Option<usize> {
+        match self.moves.last() {
+            Some(Move {action: Action::Play(pos), ..}) => Some(*pos),
+            _ => None
+        }
+    }
+    pub fn score(&self) -> [f32; 2] {
+        //Dead stones are removed
+        let board: Vec<Stone> = self.board.iter().zip(&self.dead)
+            .map(|(&stone, &dead)| if dead {Stone::Empty} else {stone})
+            .collect();
         //Determine territory
         let mut territory = [0, 0];
-        let mut seen = vec![false; self.board.len()];
-        for pos in 0..self.board.len() {
-            if self.board[pos] == Stone::Empty && !seen[pos] {
-                let group = connected_group(self.board_size, &self.board, pos);
+        let mut seen = vec![false; board.len()];
+        for pos in 0..board.len() {
+            if board[pos] == Stone::Empty && !seen[pos] {
+                let group = connected_group(self.width, &board, pos);
                 let mut bordered = [false, false];
                 for &pos in &group {
                     seen[pos] = true;
-                    for neighbor in neighbors(self.board_size, &self.board, pos) {
-                        match self.board[neighbor] {
+                    for neighbor in neighbors(self.width, &board, pos) {
+                        match board[neighbor] {
                             Stone::Black => bordered[0] = true,
                             Stone::White => bordered[1] = true,
                             _ => ()
@@ -333,16 +770,34 @@ impl Game {
                 }
             }
         }
-        //Count stones
-        let mut counts = [0, 0];
-        for stone in &self.board {
-            match stone {
-                Stone::Black => counts[0] += 1,
-                Stone::White => counts[1] += 1,
-                _ => ()
+        let points = match self.ruleset.scoring() {
+            Scoring::Area => {
+                //Count stones
+                let mut counts = [0, 0];
+                for stone in &board {
+                    match stone {
+                        Stone::Black => counts[0] += 1,
+                        Stone::White => counts[1] += 1,
+                        _ => ()
+                    }
+                }
+                [territory[0] + counts[0], territory[1] + counts[1]]
+            },
+            Scoring::Territory => {
+                //Count prisoners
+                let mut prisoners = self.captures;
+                for (pos, &dead) in self.dead.iter().enumerate() {
+                    match self.board[pos] {
+                        Stone::Black if dead => prisoners[1] += 1,
+                        Stone::White if dead => prisoners[0] += 1,
+                        _ => ()
+                    }
+                }
+                [territory[0] + prisoners[0], territory[1] + prisoners[1]]
             }
-        }
-        [territory[0] + counts[0], territory[1] + counts[1]]
+        };
+        //Komi goes to White
+        [points[0] as f32, points[1] as f32 + self.komi]
     }
 }
 
@@ -374,15 +829,320 @@ mod tests {
         assert!(group == expected);
     }
     #[test]
+    fn test_star_points() {
+        //9x9: third line
+        assert_eq!(star_points(9, 9, 2), Some(vec![56, 24]));
+        assert_eq!(star_points(9, 9, 5), Some(vec![56, 24, 20, 60, 40]));
+        //19x19: fourth line, in GTP order
+        assert_eq!(star_points(19, 19, 9), Some(vec![288, 72, 60, 300, 174, 186, 294, 66, 180]));
+        assert_eq!(star_points(13, 13, 4).unwrap().len(), 4);
+        assert_eq!(star_points(12, 12, 5), None);
+        assert_eq!(star_points(5, 5, 2), None);
+        assert_eq!(star_points(9, 13, 2), None);
+    }
+    #[test]
+    fn test_fixed_handicap() {
+        let game = Game::new(13, 0.5, 4, Placement::Fixed, Ruleset::Japanese).unwrap();
+        assert_eq!(game.turn, Turn::White);
+        for pos in [42, 48, 120, 126] {
+            assert_eq!(game.board[pos], Stone::Black);
+        }
+        assert!(Game::new(6, 0.5, 2, Placement::Fixed, Ruleset::Japanese).is_err());
+    }
+    #[test]
+    fn test_board_sizes() {
+        for n in [2, 7, 25] {
+            let game = Game::new(n, 0.0, 1, Placement::Free, Ruleset::Chinese).unwrap();
+            assert_eq!(game.board.len(), n * n);
+            assert!(game.valid_moves.iter().all(|&legal| legal));
+        }
+        assert!(Game::new(1, 0.0, 1, Placement::Free, Ruleset::Chinese).is_err());
+        assert!(Game::new(26, 0.0, 1, Placement::Free, Ruleset::Chinese).is_err());
+        assert!(Game::rectangular(usize::MAX, usize::MAX, 0.0, 1, Placement::Free, Ruleset::Chinese).is_err());
+        //Handicap stones must leave a free point
+        assert!(Game::new(2, 0.0, 4, Placement::Free, Ruleset::Chinese).is_err());
+        assert!(Game::rectangular(9, 13, 0.0, 2, Placement::Fixed, Ruleset::Chinese).is_err());
+        //3 wide, 2 high: Black captures White's corner stone at the bottom right
+        let mut game = Game::rectangular(3, 2, 0.0, 1, Placement::Free, Ruleset::Chinese).unwrap();
+        assert_eq!(game.board.len(), 6);
+        game.play(Stone::Black, 4).unwrap();
+        game.play(Stone::White, 5).unwrap();
+        game.play(Stone::Black, 2).unwrap();
+        assert_eq!(game.moves[2].captures, vec![5]);
+        game.pass(Stone::White).unwrap();
+        game.pass(Stone::Black).unwrap();
+        assert_eq!(game.score(), [6.0, 0.0]);
+    }
+    #[test]
     fn test_scoring() {
-        let mut game = Game::new(5, 0, 1).unwrap();
-        game.board_size = 3;
+        let mut game = Game::new(5, 0.0, 1, Placement::Free, Ruleset::TrompTaylor).unwrap();
+        game.width = 3;
+        game.height = 3;
         game.board = vec![
             Stone::Empty, Stone::Empty, Stone::Black,
             Stone::Black, Stone::Black, Stone::Empty,
             Stone::Black, Stone::White, Stone::Empty
         ];
-        let expected = [6, 1];
+        game.dead = vec![false; 9];
+        let expected = [6.0, 1.0];
         assert_eq!(game.score(), expected);
     }
+    #[test]
+    fn test_dead_stones() {
+        let mut game = Game::new(5, 0.0, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        //White wall on the second column, Black wall on the third column
+        for (black, white) in [(2, 1), (7, 6), (12, 11), (17, 16), (22, 21)] {
+            game.play(Stone::Black, black).unwrap();
+            game.play(Stone::White, white).unwrap();
+        }
+        //White invades Black's area
+        for white in [24, 23, 19] {
+            game.pass(Stone::Black).unwrap();
+            game.play(Stone::White, white).unwrap();
+        }
+        game.pass(Stone::Black).unwrap();
+        game.pass(Stone::White).unwrap();
+        assert_eq!(game.turn, Turn::Scoring);
+        //White's right side stones are dead
+        game.mark(Stone::Black, 24).unwrap();
+        assert!(game.dead[23] && game.dead[19]);
+        game.accept(Stone::Black).unwrap();
+        assert_eq!(game.turn, Turn::Scoring);
+        game.accept(Stone::White).unwrap();
+        assert_eq!(game.turn, Turn::End);
+        //Black: 10 territory + 3 prisoners, White: 5 territory
+        assert_eq!([game.black_score, game.white_score], [13.0, 5.0]);
+        //Area scoring of the same position
+        game.ruleset = Ruleset::Chinese;
+        assert_eq!(game.score(), [15.0, 10.0]);
+    }
+    #[test]
+    fn test_suggest() {
+        let mut game = Game::new(5, 0.0, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        game.play(Stone::Black, 2).unwrap();
+        game.play(Stone::White, 24).unwrap();
+        let estimate = GameResult::Score {winner: Stone::Black, margin: 26.0};
+        assert!(game.suggest(&[24], estimate).is_err());
+        game.pass(Stone::Black).unwrap();
+        game.pass(Stone::White).unwrap();
+        let mut marked = game.clone();
+        //Only stones are marked
+        assert!(game.suggest(&[24, 3], estimate).unwrap());
+        assert!(game.dead[24] && !game.dead[3]);
+        assert_eq!(game.estimate, Some(estimate));
+        assert_eq!([game.black_score, game.white_score], [25.0, 0.0]);
+        //Players' own marks and acceptance are kept
+        let mut accepted = marked.clone();
+        accepted.accept(Stone::Black).unwrap();
+        assert!(!accepted.suggest(&[24], estimate).unwrap());
+        assert!(!accepted.dead[24]);
+        assert_eq!(accepted.accepted, [true, false]);
+        assert_eq!(accepted.estimate, Some(estimate));
+        marked.mark(Stone::White, 2).unwrap();
+        assert!(!marked.suggest(&[24], estimate).unwrap());
+        assert!(marked.dead[2] && !marked.dead[24]);
+    }
+    #[test]
+    fn test_komi() {
+        assert!(Game::new(9, 6.25, 1, Placement::Free, Ruleset::Chinese).is_err());
+        //At most the number of points on the board, either way
+        assert!(Game::new(9, 81.0, 1, Placement::Free, Ruleset::Chinese).is_ok());
+        assert!(Game::new(9, -81.0, 1, Placement::Free, Ruleset::Chinese).is_ok());
+        assert!(Game::new(9, 81.5, 1, Placement::Free, Ruleset::Chinese).is_err());
+        assert!(Game::new(9, -1000.0, 1, Placement::Free, Ruleset::Chinese).is_err());
+        let mut game = Game::new(5, 6.5, 1, Placement::Free, Ruleset::Chinese).unwrap();
+        game.play(Stone::Black, 12).unwrap();
+        game.pass(Stone::White).unwrap();
+        game.pass(Stone::Black).unwrap();
+        game.accept(Stone::Black).unwrap();
+        game.accept(Stone::White).unwrap();
+        assert_eq!([game.black_score, game.white_score], [25.0, 6.5]);
+        assert_eq!(game.result, Some(GameResult::Score {winner: Stone::Black, margin: 18.5}));
+        assert_eq!(game.result.unwrap().to_string(), "B+18.5");
+    }
+    #[test]
+    fn test_results() {
+        let mut game = Game::new(5, 0.0, 2, Placement::Free, Ruleset::Chinese).unwrap();
+        assert!(game.resign(Stone::White).is_err());
+        game.resign(Stone::Black).unwrap();
+        assert_eq!(game.result, Some(GameResult::Resignation {winner: Stone::White}));
+        assert!(game.abort().is_err());
+        let mut game = Game::new(5, 0.0, 1, Placement::Free, Ruleset::Chinese).unwrap();
+        game.play(Stone::Black, 0).unwrap();
+        game.timeout(Stone::White).unwrap();
+        assert_eq!(game.result.unwrap().to_string(), "B+T");
+        let mut game = Game::new(5, 0.0, 1, Placement::Free, Ruleset::Chinese).unwrap();
+        game.abort().unwrap();
+        assert_eq!(game.turn, Turn::End);
+        assert_eq!(game.result.unwrap().to_string(), "Void");
+    }
+    #[test]
+    fn test_moves() {
+        let mut game = Game::new(5, 0.0, 2, Placement::Free, Ruleset::TrompTaylor).unwrap();
+        game.play_handicap(Stone::Black, &[1, 24]).unwrap();
+        assert!(!game.valid_moves[1]);
+        game.play(Stone::White, 0).unwrap();
+        game.play(Stone::Black, 5).unwrap();
+        assert_eq!(game.last_move(), Some(5));
+        game.pass(Stone::White).unwrap();
+        assert_eq!(game.last_move(), None);
+        let actions: Vec<Action> = game.moves.iter().map(|x| x.action.clone()).collect();
+        assert_eq!(actions, vec![
+            Action::Handicap(vec![1, 24]),
+            Action::Play(0),
+            Action::Play(5),
+            Action::Pass
+        ]);
+        //Black captures White's corner stone
+        assert_eq!(game.moves[2].captures, vec![0]);
+        assert_eq!(game.board[0], Stone::Empty);
+    }
+    #[test]
+    fn test_suicide() {
+        for (ruleset, legal) in [(Ruleset::Japanese, false), (Ruleset::TrompTaylor, true)] {
+            let mut game = Game::new(5, 0.0, 1, Placement::Free, ruleset).unwrap();
+            for (stone, pos) in [(Stone::Black, 2), (Stone::White, 0), (Stone::Black, 5), (Stone::White, 24), (Stone::Black, 6)] {
+                game.play(stone, pos).unwrap();
+            }
+            //White 1 would remove its own two stones
+            assert_eq!(game.valid_moves[1], legal);
+        }
+    }
+    #[test]
+    fn test_ko() {
+        let mut game = Game::new(5, 0.0, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        for (stone, pos) in [
+            (Stone::Black, 1), (Stone::White, 2),
+            (Stone::Black, 5), (Stone::White, 8),
+            (Stone::Black, 11), (Stone::White, 12),
+            (Stone::Black, 20), (Stone::White, 6),
+            (Stone::Black, 7)
+        ] {
+            game.play(stone, pos).unwrap();
+        }
+        assert_eq!(game.moves[8].captures, vec![6]);
+        //No immediate recapture
+        assert!(!game.valid_moves[6]);
+        game.play(Stone::White, 24).unwrap();
+        game.play(Stone::Black, 19).unwrap();
+        assert!(game.valid_moves[6]);
+    }
+    #[test]
+    fn test_undo() {
+        let moves = [
+            (Stone::Black, 1), (Stone::White, 2),
+            (Stone::Black, 5), (Stone::White, 8),
+            (Stone::Black, 11), (Stone::White, 12),
+            (Stone::Black, 20), (Stone::White, 6),
+            (Stone::Black, 7), (Stone::White, 24)
+        ];
+        let mut game = Game::new(5, 0.0, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        assert!(!game.can_undo(Stone::Black));
+        for (stone, pos) in moves {
+            game.play(stone, pos).unwrap();
+        }
+        //Black takes back the ko capture and White's reply
+        assert_eq!(game.undo(Stone::Black).unwrap(), 2);
+        let mut expected = Game::new(5, 0.0, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        for (stone, pos) in &moves[..8] {
+            expected.play(*stone, *pos).unwrap();
+        }
+        assert_eq!(game.board, expected.board);
+        assert_eq!(game.history, expected.history);
+        assert_eq!(game.hashes, expected.hashes);
+        assert_eq!(game.valid_moves, expected.valid_moves);
+        assert_eq!(game.captures, [0, 0]);
+        assert_eq!(game.turn, Turn::Black);
+        //White only takes back its own move
+        assert_eq!(game.undo(Stone::White).unwrap(), 1);
+        assert_eq!(game.board[6], Stone::Empty);
+        assert_eq!(game.turn, Turn::White);
+        game.play(Stone::White, 6).unwrap();
+        game.play(Stone::Black, 7).unwrap();
+        assert_eq!(game.captures, [1, 0]);
+        //Passes are taken back too
+        game.pass(Stone::White).unwrap();
+        game.pass(Stone::Black).unwrap();
+        assert_eq!(game.turn, Turn::Scoring);
+        assert!(game.undo(Stone::Black).is_err());
+    }
+    //Legal moves as found by replaying every move on a copy of the board
+    fn naive_moves(game: &Game, boards: &[Vec<Stone>], movers: &[Stone], stone: Stone) -> Vec<bool> {
+        let n = game.width;
+        let alive = |board: &[Stone], group: &[usize]| group.iter()
+            .any(|&x| neighbors(n, board, x).iter().any(|&y| board[y] == Stone::Empty));
+        let place = |board: &mut Vec<Stone>, pos: usize| {
+            board[pos] = stone;
+            let mut captures = Vec::new();
+            for neighbor in neighbors(n, board, pos) {
+                if board[neighbor] == stone.opponent() {
+                    let group = connected_group(n, board, neighbor);
+                    if !alive(board, &group) {
+                        for &x in &group {
+                            board[x] = Stone::Empty;
+                        }
+                        captures.extend(group);
+                    }
+                }
+            }
+            let group = connected_group(n, board, pos);
+            if !alive(board, &group) {
+                for &x in &group {
+                    board[x] = Stone::Empty;
+                }
+                captures.extend(group);
+            }
+            captures
+        };
+        (0..game.board.len()).map(|pos| {
+            if game.board[pos] != Stone::Empty {
+                return false
+            }
+            let mut board = game.board.clone();
+            let captures = place(&mut board, pos);
+            let repeats = match game.ruleset.ko() {
+                Ko::Simple => boards.len() >= 2 && boards[boards.len() - 2] == board,
+                Ko::Positional => boards.contains(&board),
+                Ko::Situational => boards.iter().zip(movers).any(|(x, &mover)| mover == stone && *x == board)
+            };
+            (!captures.contains(&pos) || game.ruleset.suicide()) && (captures.is_empty() || !repeats)
+        }).collect()
+    }
+    #[test]
+    fn test_valid_moves() {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+        let mut rng = StdRng::seed_from_u64(7);
+        for ruleset in [Ruleset::Japanese, Ruleset::Chinese, Ruleset::NewZealand] {
+            let mut game = Game::new(5, 0.0, 1, Placement::Free, ruleset).unwrap();
+            let mut boards = vec![game.board.clone()];
+            let mut movers = vec![Stone::White];
+            for _ in 0..200 {
+                let stone = if game.turn == Turn::Black {Stone::Black} else {Stone::White};
+                assert_eq!(game.valid_moves, naive_moves(&game, &boards, &movers, stone));
+                let legal: Vec<usize> = (0..25).filter(|&x| game.valid_moves[x]).collect();
+                if legal.is_empty() || rng.gen_ratio(1, 20) {
+                    game.pass(stone).unwrap();
+                } else {
+                    game.play(stone, legal[rng.gen_range(0..legal.len())]).unwrap();
+                }
+                if game.turn == Turn::Scoring {
+                    break
+                }
+                boards.push(game.board.clone());
+                movers.push(stone);
+            }
+        }
+    }
+    #[test]
+    fn test_pass_stones() {
+        let mut game = Game::new(5, 0.0, 1, Placement::Free, Ruleset::Aga).unwrap();
+        game.play(Stone::Black, 0).unwrap();
+        game.pass(Stone::White).unwrap();
+        game.pass(Stone::Black).unwrap();
+        //White must pass last
+        assert_eq!(game.turn, Turn::White);
+        game.pass(Stone::White).unwrap();
+        assert_eq!(game.turn, Turn::Scoring);
+        assert_eq!(game.captures, [2, 1]);
+    }
 }
@@ -0,0 +1,346 @@
+use crate::{
+    model::Ruleset,
+    config::EngineProfile,
+    gtp::{Client, GtpError},
+    engine
+};
+use serde::Serialize;
+use tokio::{
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    time::{self, Instant}
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    error,
+    sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+    time::Duration
+};
+
+pub enum PoolError {
+    //No free slot within the wait time
+    Full,
+    Engine(GtpError)
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Full => write!(f, "All engines are busy, try again later"),
+            PoolError::Engine(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl fmt::Debug for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for PoolError {}
+
+impl From<GtpError> for PoolError {
+    fn from(value: GtpError) -> Self {
+        PoolError::Engine(value)
+    }
+}
+
+//Processes are only reused for the same command line and timeout
+#[derive(Clone, PartialEq, Eq)]
+struct Key {
+    command: String,
+    args: Vec<String>,
+    timeout: Duration
+}
+
+impl Key {
+    fn new(profile: &EngineProfile, ruleset: Ruleset) -> Key {
+        Key {
+            command: profile.command.clone(),
+            args: engine::args(profile, ruleset),
+            timeout: profile.timeout()
+        }
+    }
+}
+
+//Engine process checked out of the pool, its slot is freed when dropped
+pub struct Engine {
+    pub client: Client,
+    key: Key,
+    permit: OwnedSemaphorePermit
+}
+
+enum Reserved {
+    Permit(OwnedSemaphorePermit),
+    Engine(Box<Engine>)
+}
+
+//Slot held for a bot's engine from the time its game is created
+pub struct Slot(Reserved);
+
+#[derive(Serialize)]
+pub struct PoolStats {
+    pub limit: usize,
+    pub busy: usize,
+    pub idle: usize,
+    //Totals since startup
+    pub spawned: usize,
+    pub reused: usize,
+    pub rejected: usize
+}
+
+/*
+    Engine processes shared by all games, hints and scoring
+    Every process, busy or idle, holds one of `limit` slots
+    Idle processes are reset with clear_board and reused, or stopped
+    when a slot is needed for a different engine
+*/
+pub struct Pool {
+    limit: usize,
+    wait: Duration,
+    permits: Arc<Semaphore>,
+    idle: Mutex<VecDeque<Engine>>,
+    //Wakes checkouts waiting for a slot when an engine is returned
+    returned: Notify,
+    spawned: AtomicUsize,
+    reused: AtomicUsize,
+    rejected: AtomicUsize
+}
+
+impl Pool {
+    pub fn new(limit: usize, wait: Duration) -> Pool {
+        Pool {
+            limit,
+            wait,
+            permits: Arc::new(Semaphore::new(limit)),
+            idle: Mutex::new(VecDeque::new()),
+            returned: Notify::new(),
+            spawned: AtomicUsize::new(0),
+            reused: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0)
+        }
+    }
+    //Slots for engines that start right away, taking idle engines when they fit
+    //None without room for all of them
+    pub fn reserve(&self, engines: &[(&EngineProfile, Ruleset)]) -> Option<Vec<Slot>> {
+        let mut idle = self.idle.lock().unwrap();
+        let mut slots = Vec::with_capacity(engines.len());
+        if self.permits.available_permits() + idle.len() >= engines.len() {
+            for &(profile, ruleset) in engines {
+                let key = Key::new(profile, ruleset);
+                if let Some(index) = idle.iter().position(|engine| engine.key == key) {
+                    slots.extend(idle.remove(index).map(|engine| Slot(Reserved::Engine(Box::new(engine)))));
+                    continue
+                }
+                //Make room by stopping the longest idle engines
+                let permit = loop {
+                    match self.permits.clone().try_acquire_owned() {
+                        Ok(permit) => break Some(permit),
+                        Err(_) => if idle.pop_front().is_none() {
+                            break None
+                        }
+                    }
+                };
+                match permit {
+                    Some(permit) => slots.push(Slot(Reserved::Permit(permit))),
+                    None => break
+                }
+            }
+        }
+        if slots.len() == engines.len() {
+            Some(slots)
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+    //Idle engine for the key, checked with clear_board
+    async fn reuse(&self, key: &Key) -> Option<Engine> {
+        loop {
+            let mut engine = {
+                let mut idle = self.idle.lock().unwrap();
+                let index = idle.iter().position(|engine| &engine.key == key)?;
+                idle.remove(index)?
+            };
+            //Engines that died while idle are dropped
+            if engine.client.clear_board().await.is_ok() {
+                return Some(engine)
+            }
+        }
+    }
+    //Free slot, or an idle engine, within the wait time
+    async fn permit(&self, key: &Key) -> Result<Result<OwnedSemaphorePermit, Engine>, PoolError> {
+        let deadline = Instant::now() + self.wait;
+        loop {
+            let returned = self.returned.notified();
+            if let Some(engine) = self.reuse(key).await {
+                return Ok(Err(engine))
+            }
+            if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+                return Ok(Ok(permit))
+            }
+            //Make room by stopping the longest idle engine
+            let evicted = self.idle.lock().unwrap().pop_front();
+            if evicted.is_some() {
+                continue
+            }
+            let wait = async {
+                tokio::select! {
+                    permit = self.permits.clone().acquire_owned() => permit.ok(),
+                    _ = returned => None
+                }
+            };
+            match time::timeout_at(deadline, wait).await {
+                Ok(Some(permit)) => return Ok(Ok(permit)),
+                Ok(None) => continue,
+                Err(_) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(PoolError::Full)
+                }
+            }
+        }
+    }
+    pub async fn checkout(&self, profile: &EngineProfile, ruleset: Ruleset) -> Result<Engine, PoolError> {
+        let key = Key::new(profile, ruleset);
+        match self.permit(&key).await? {
+            Ok(permit) => self.spawn(profile, ruleset, key, permit),
+            Err(engine) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                Ok(engine)
+            }
+        }
+    }
+    //Engine for a reserved slot, which never waits
+    pub async fn checkout_reserved(&self, profile: &EngineProfile, ruleset: Ruleset, slot: Slot) -> Result<Engine, PoolError> {
+        let key = Key::new(profile, ruleset);
+        let permit = match slot.0 {
+            Reserved::Engine(mut engine) => {
+                if engine.client.clear_board().await.is_ok() {
+                    self.reused.fetch_add(1, Ordering::Relaxed);
+                    return Ok(*engine)
+                }
+                //Died while idle, its slot starts a new one
+                engine.permit
+            },
+            Reserved::Permit(permit) => permit
+        };
+        self.spawn(profile, ruleset, key, permit)
+    }
+    fn spawn(&self, profile: &EngineProfile, ruleset: Ruleset, key: Key, permit: OwnedSemaphorePermit) -> Result<Engine, PoolError> {
+        let client = engine::spawn(profile, ruleset)?;
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+        Ok(Engine {client, key, permit})
+    }
+    //Keep a healthy engine for later games
+    pub fn checkin(&self, engine: Engine) {
+        self.checkin_at_most(engine, self.limit);
+    }
+    //Keep at most `keep` idle engines like this one, stopping the longest idle
+    pub fn checkin_at_most(&self, engine: Engine, keep: usize) {
+        {
+            let mut idle = self.idle.lock().unwrap();
+            let alike: Vec<usize> = idle.iter().enumerate()
+                .filter(|(_, idle)| idle.key == engine.key)
+                .map(|(index, _)| index)
+                .collect();
+            for &index in alike.iter().take((alike.len() + 1).saturating_sub(keep)).rev() {
+                idle.remove(index);
+            }
+            if keep > 0 {
+                idle.push_back(engine);
+            }
+        }
+        self.returned.notify_waiters();
+    }
+    pub fn stats(&self) -> PoolStats {
+        let idle = self.idle.lock().unwrap().len();
+        PoolStats {
+            limit: self.limit,
+            busy: self.limit - self.permits.available_permits() - idle,
+            idle,
+            spawned: self.spawned.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    //Answers every command with an empty success
+    fn profile(name: &str) -> EngineProfile {
+        EngineProfile {
+            name: name.to_string(),
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "while read id command; do printf '=%s\\n\\n' \"$id\"; done".to_string(),
+                name.to_string()
+            ],
+            board_sizes: Vec::new(),
+            strength: String::new(),
+            gnugo_rules: false,
+            timeout: 5
+        }
+    }
+    #[tokio::test]
+    async fn test_pool() {
+        let pool = Pool::new(1, Duration::ZERO);
+        let (first, second) = (profile("first"), profile("second"));
+        let engine = pool.checkout(&first, Ruleset::Chinese).await.unwrap();
+        assert!(matches!(pool.checkout(&first, Ruleset::Chinese).await, Err(PoolError::Full)));
+        pool.checkin(engine);
+        //Reused for the same engine
+        let engine = pool.checkout(&first, Ruleset::Chinese).await.unwrap();
+        pool.checkin(engine);
+        //Stopped to make room for another one
+        let engine = pool.checkout(&second, Ruleset::Chinese).await.unwrap();
+        let stats = pool.stats();
+        assert_eq!((stats.busy, stats.idle), (1, 0));
+        assert_eq!((stats.spawned, stats.reused, stats.rejected), (2, 1, 1));
+        drop(engine);
+        assert_eq!(pool.stats().busy, 0);
+    }
+    #[tokio::test]
+    async fn test_checkin_at_most() {
+        let pool = Pool::new(3, Duration::ZERO);
+        let (first, second) = (profile("first"), profile("second"));
+        let engines = [
+            pool.checkout(&first, Ruleset::Chinese).await.unwrap(),
+            pool.checkout(&first, Ruleset::Chinese).await.unwrap(),
+            pool.checkout(&second, Ruleset::Chinese).await.unwrap()
+        ];
+        for engine in engines {
+            pool.checkin_at_most(engine, 1);
+        }
+        //One of each engine is kept, the stopped one frees its slot
+        let stats = pool.stats();
+        assert_eq!((stats.busy, stats.idle), (0, 2));
+    }
+    #[tokio::test]
+    async fn test_reserve() {
+        let pool = Pool::new(2, Duration::ZERO);
+        let (first, second) = (profile("first"), profile("second"));
+        let engine = pool.checkout(&first, Ruleset::Chinese).await.unwrap();
+        pool.checkin(engine);
+        let slots = pool.reserve(&[(&first, Ruleset::Chinese), (&second, Ruleset::Chinese)]).unwrap();
+        //Reserved slots are taken, even before their engines start
+        assert!(pool.reserve(&[(&first, Ruleset::Chinese)]).is_none());
+        assert!(matches!(pool.checkout(&first, Ruleset::Chinese).await, Err(PoolError::Full)));
+        let mut slots = slots.into_iter();
+        let engine = pool.checkout_reserved(&first, Ruleset::Chinese, slots.next().unwrap()).await.unwrap();
+        let other = pool.checkout_reserved(&second, Ruleset::Chinese, slots.next().unwrap()).await.unwrap();
+        let stats = pool.stats();
+        assert_eq!((stats.busy, stats.idle), (2, 0));
+        //The idle engine was reused for its own profile
+        assert_eq!((stats.spawned, stats.reused, stats.rejected), (2, 1, 2));
+        drop((engine, other));
+        //Idle engines of other profiles are stopped to make room
+        let engine = pool.checkout(&first, Ruleset::Chinese).await.unwrap();
+        pool.checkin(engine);
+        assert!(pool.reserve(&[(&second, Ruleset::Chinese), (&second, Ruleset::Chinese)]).is_some());
+        assert_eq!(pool.stats().idle, 0);
+    }
+}
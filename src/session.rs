@@ -1,54 +1,80 @@
 use crate::{
-    model::{Game, Stone, Turn, Settings},
-    timer::Timer,
-    engine
+    model::{Action, Game, GameResult, Placement, Stone, Turn, Settings},
+    timer::{Timer, Clocks, Clock, TimeControl},
+    engine,
+    gtp::Vertex,
+    analysis::{Analysis, AnalysisError},
+    sgf::{self, SgfError},
+    storage::{Storage, Writer},
+    pool::{Pool, PoolError, Slot},
+    config::{Config, EngineProfile}
 };
 use tokio::sync::{mpsc, broadcast, oneshot};
 use serde::{Serialize, Deserialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Packet {
-    //Board state
+    //Board state, row by row from the top left
+    pub width: usize,
+    pub height: usize,
     pub board: Vec<u8>,
     pub moves: Vec<bool>,
+    pub last_move: Option<usize>,
+    pub dead: Vec<bool>,
     pub turn: String,
     //Occupancy
     pub black_occupied: bool,
     pub white_occupied: bool,
     //Score
-    pub black_score: u32,
-    pub white_score: u32,
+    pub black_accepted: bool,
+    pub white_accepted: bool,
+    pub black_score: f32,
+    pub white_score: f32,
+    pub result: Option<GameResult>,
+    pub estimate: Option<GameResult>,
+    //Players who agreed to engine hints
+    pub black_hints: bool,
+    pub white_hints: bool,
     //Time control
     pub black_time: u64,
-    pub white_time: u64
+    pub white_time: u64,
+    pub black_periods: u32,
+    pub white_periods: u32
 }
 
 impl Packet {
     fn new(
         game: &Game,
-        timers: &[Timer],
-        occupancy: &[bool]
+        clocks: &Clocks,
+        lobby: &Timer,
+        occupancy: &[bool],
+        hints: &[bool]
     ) -> Packet {
         Packet {
             //Board state
+            width: game.width,
+            height: game.height,
             board: game.board.iter().map(|x| match x {
                 Stone::Empty => 0,
                 Stone::Black => 1,
                 Stone::White => 2
             }).collect(),
             moves: game.valid_moves.clone(),
-            turn: if timers[2].running() {
+            last_move: game.last_move(),
+            dead: game.dead.clone(),
+            turn: if lobby.running() {
                 "wait".into()
             } else {
                 match game.turn {
                     Turn::Handicap => "handicap".into(),
                     Turn::Black => "black".into(),
                     Turn::White => "white".into(),
+                    Turn::Scoring => "scoring".into(),
                     Turn::End => "end".into()
                 }
             },
@@ -56,81 +82,359 @@ impl Packet {
             black_occupied: occupancy[0],
             white_occupied: occupancy[1],
             //Score
+            black_accepted: game.accepted[0],
+            white_accepted: game.accepted[1],
             black_score: game.black_score,
             white_score: game.white_score,
+            result: game.result,
+            estimate: game.estimate,
+            black_hints: hints[0],
+            white_hints: hints[1],
             //Time control
-            black_time: if timers[2].running() {
-                timers[2].time().as_secs()
+            black_time: if lobby.running() {
+                lobby.time().as_secs()
             } else {
-                timers[0].time().as_secs()
+                clocks.get(Stone::Black).time().as_secs()
             },
-            white_time: if timers[2].running() {
-                timers[2].time().as_secs()
+            white_time: if lobby.running() {
+                lobby.time().as_secs()
             } else {
-                timers[1].time().as_secs()
-            }
+                clocks.get(Stone::White).time().as_secs()
+            },
+            black_periods: clocks.get(Stone::Black).periods(),
+            white_periods: clocks.get(Stone::White).periods()
         }
     }
 }
 
+//Broadcast to players and spectators
+#[derive(Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    Packet(Packet),
+    Shutdown,
+    //Only sent to the player who asked
+    Hint(Analysis),
+    #[serde(rename = "hint-error")]
+    HintError {message: String},
+    //A player asks to take back their last move, the opponent answers
+    #[serde(rename = "undo-request")]
+    UndoRequest {stone: Stone},
+    #[serde(rename = "undo-declined")]
+    UndoDeclined {stone: Stone},
+    //The scoring engine marked dead stones before the players did
+    #[serde(rename = "dead-suggested")]
+    DeadSuggested,
+    Chat(Chat),
+    //Only sent to the player who wrote the message
+    #[serde(rename = "chat-error")]
+    ChatError {message: String}
+}
+
+impl Event {
+    //Events shown to spectators, player chat stays with the players
+    pub fn public(&self) -> bool {
+        !matches!(self, Event::Chat(Chat {channel: Channel::Players, ..}))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Players,
+    Spectators
+}
+
+#[derive(Clone, Serialize)]
+pub struct Chat {
+    pub channel: Channel,
+    //Empty for spectators
+    pub stone: Stone,
+    pub text: String,
+    //Milliseconds since the Unix epoch
+    pub time: u64
+}
+
+//Longest chat message in characters
+pub const CHAT_LENGTH: usize = 500;
+//Chat messages kept for players and spectators who join later
+const CHAT_HISTORY: usize = 50;
+
+//Session state kept across server restarts, the game itself is in storage
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: usize,
+    pub engines: [Option<String>; 2],
+    pub clocks: Clocks,
+    #[serde(default)]
+    pub move_delay: Duration,
+    #[serde(default)]
+    pub rated: bool,
+    #[serde(default)]
+    pub takebacks: bool
+}
+
+//Engine restarts per side before the game ends with an engine failure
+const ENGINE_RESTARTS: u32 = 2;
+//Pause before asking a full pool for an engine again
+const ENGINE_RETRY: Duration = Duration::from_secs(1);
+
 pub enum Message {
     //Lobby
     Join(Stone, oneshot::Sender<bool>),
     Leave(Stone),
+    Abandon(Stone),
     Expire,
+    Start,
     //Game
+    Turn(Stone),
     Handicap(Stone, Vec<usize>),
     Play(Stone, usize),
     Pass(Stone),
     Resign(Stone),
+    Timeout(Stone),
+    //Takebacks
+    Undo(Stone),
+    UndoReply(Stone, bool),
+    EngineFailure(Stone, PoolError),
+    EngineRetry(Stone),
+    //Scoring
+    Mark(Stone, usize),
+    Accept(Stone),
+    Estimate(Vec<usize>, GameResult),
+    BotDead(Stone, Vec<usize>),
+    //Hints
+    AllowHints(Stone, bool),
+    Hint(oneshot::Sender<Result<Game, AnalysisError>>),
+    //Chat
+    Chat(Stone, Channel, String),
+    //Listen for events, starting after the chat history
+    Subscribe(oneshot::Sender<(broadcast::Receiver<Event>, Vec<Chat>)>),
     //Utility
     Ping,
     Packet(oneshot::Sender<Packet>),
-    Query(oneshot::Sender<Settings>)
+    Query(oneshot::Sender<Settings>),
+    Record(oneshot::Sender<Result<String, SgfError>>),
+    Snapshot(oneshot::Sender<Option<Snapshot>>)
+}
+
+fn settings(
+    game: &Game,
+    time_control: TimeControl,
+    names: &[String; 2],
+    rated: bool,
+    takebacks: bool
+) -> Settings {
+    Settings {
+        board_size: game.width as u32,
+        board_height: (game.height != game.width).then_some(game.height as u32),
+        komi: game.komi,
+        handicap: game.handicap,
+        placement: game.placement,
+        ruleset: game.ruleset,
+        time_control,
+        black_player: names[0].clone(),
+        white_player: names[1].clone(),
+        rated,
+        takebacks
+    }
+}
+
+//Tell the engine its remaining time before asking for a move
+fn genmove(engine: &mpsc::UnboundedSender<engine::Message>, clock: &Clock) {
+    let (time, stones) = clock.time_left();
+    let _ = engine.send(engine::Message::TimeLeft(time, stones));
+    let _ = engine.send(engine::Message::Genmove);
+}
+
+//Start an engine and replay the game so far
+fn start_engine(
+    pool: &Arc<Pool>,
+    profile: &EngineProfile,
+    stone: Stone,
+    game: &Game,
+    time_control: TimeControl,
+    slot: Option<Slot>,
+    sender: &mpsc::UnboundedSender<Message>
+) -> mpsc::UnboundedSender<engine::Message> {
+    let client = engine::engine(pool.clone(), profile, stone, game.width as u32, game.komi, game.ruleset, slot, sender.clone());
+    let _ = client.send(engine::Message::TimeSettings(time_control));
+    for entry in &game.moves {
+        match &entry.action {
+            Action::Handicap(positions) if game.placement == Placement::Fixed => {
+                let _ = client.send(engine::Message::FixedHandicap(positions.clone()));
+            },
+            Action::Handicap(positions) => for &position in positions {
+                let _ = client.send(engine::Message::Play(entry.stone, Vertex::Point(position)));
+            },
+            Action::Play(position) => {
+                let _ = client.send(engine::Message::Play(entry.stone, Vertex::Point(*position)));
+            },
+            Action::Pass => {
+                let _ = client.send(engine::Message::Play(entry.stone, Vertex::Pass));
+            },
+            Action::Resign => ()
+        }
+    }
+    client
+}
+
+//Ask the scoring engine, if any, for dead stones and a result
+//GTP engines only play on square boards
+fn estimate(config: &Config, pool: &Arc<Pool>, game: &Game, sender: &mpsc::UnboundedSender<Message>) {
+    let Some(profile) = config.scoring_engine.as_ref().and_then(|id| config.engines.get(id)) else {
+        return
+    };
+    if game.width != game.height {
+        return
+    }
+    let profile = profile.clone();
+    let pool = pool.clone();
+    let game = game.clone();
+    let sender = sender.clone();
+    tokio::spawn(async move {
+        match engine::estimate(&pool, &profile, &game).await {
+            Ok((dead, result)) => {
+                let _ = sender.send(Message::Estimate(dead, result));
+            },
+            Err(error) => eprintln!("Scoring engine {} failed: {}", profile.name, error)
+        }
+    });
+}
+
+//Hand the turn to the next player, after the move delay if it is a bot
+fn next_turn(
+    sender: &mpsc::UnboundedSender<Message>,
+    stone: Stone,
+    delay: Duration
+) -> tokio::task::JoinHandle<()> {
+    let sender = sender.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let _ = sender.send(Message::Turn(stone));
+    })
+}
+
+//Ask each bot's engine which stones it considers dead
+fn bot_dead_stones(
+    pool: &Arc<Pool>,
+    profiles: [Option<&EngineProfile>; 2],
+    bots: [bool; 2],
+    game: &Game,
+    sender: &mpsc::UnboundedSender<Message>
+) {
+    for (index, stone) in [(0, Stone::Black), (1, Stone::White)] {
+        let Some(profile) = profiles[index].filter(|_| bots[index]) else {
+            continue
+        };
+        let profile = profile.clone();
+        let pool = pool.clone();
+        let game = game.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match engine::dead_stones(&pool, &profile, &game).await {
+                Ok(dead) => {
+                    let _ = sender.send(Message::BotDead(stone, dead));
+                },
+                Err(error) => eprintln!("Engine {} could not list dead stones: {}", profile.name, error)
+            }
+        });
+    }
+}
+
+//Bots agree with a marking only if it matches their engine's dead stones
+fn accept_bots(game: &mut Game, marks: &[Option<Vec<bool>>; 2]) {
+    for (index, stone) in [(0, Stone::Black), (1, Stone::White)] {
+        if marks[index].as_ref() == Some(&game.dead) {
+            let _ = game.accept(stone);
+        }
+    }
 }
 
 pub type Sessions = HashMap<
     usize,
-    (mpsc::UnboundedSender<Message>, broadcast::Sender<Packet>)
+    (mpsc::UnboundedSender<Message>, broadcast::Sender<Event>)
 >;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn session(
     id: usize,
     sessions: Arc<Mutex<Sessions>>,
     sender: mpsc::UnboundedSender<Message>,
     mut receiver: mpsc::UnboundedReceiver<Message>,
-    broadcast: broadcast::Sender<Packet>,
+    broadcast: broadcast::Sender<Event>,
     mut game: Game,
-    bots: [bool; 2],
-    fixed_time: Duration,
-    added_time: Duration,
-    engine_command: String
+    engines: [Option<String>; 2], //Engine profile of each bot side
+    reserved: [Option<Slot>; 2], //Pool slots for the bots' first engines
+    mut clocks: Clocks,
+    move_delay: Duration, //Pause before each bot move
+    rated: bool,
+    takebacks: bool, //Players may take back moves
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+    pool: Arc<Pool>
 ) {
+    let bots = [engines[0].is_some(), engines[1].is_some()];
+    let profiles = engines.clone().map(|id| id.and_then(|id| config.engines.get(&id)));
+    let names = profiles.map(|profile| match profile {
+        Some(profile) => profile.label(),
+        None => "Human".to_string()
+    });
     let mut players = bots;
-    let session_timeout = Duration::from_secs(5 * 60);
-    let mut timers = [
-        Timer::new(fixed_time, false), //Black
-        Timer::new(fixed_time, false), //White
-        Timer::new(session_timeout, true) //Session
-    ];
+    //Bots agree to hints
+    let mut hints = bots;
+    let session_timeout = config.limits.lobby_timeout();
+    let abandon_timeout = Duration::from_secs(60);
+    let mut abandoned: [Option<tokio::task::JoinHandle<()>>; 2] = [None, None];
+    let time_control = clocks.control();
+    let mut lobby = Timer::new(session_timeout, true);
     let sender_clone = sender.clone();
     let mut handle = tokio::spawn(async move {
         tokio::time::sleep(session_timeout).await;
         let _ = sender_clone.send(Message::Expire);
     });
-    //Engine
-    let mut engine = None;
-    if bots[0] || bots[1] {
-        let stone = if bots[0] {Stone::Black} else {Stone::White};
-        engine = Some(engine::engine(engine_command, stone, game.board_size as u32, game.komi, sender.clone()));
+    //Storage
+    let writer = Writer::new(storage, id, settings(&game, time_control, &names, rated, takebacks));
+    writer.save(&game);
+    let mut stored = (game.moves.len(), game.result, game.dead.clone());
+    //Engines, one process per bot side, until dead stone marking
+    //Profiles removed from the configuration fall back to the default
+    let profiles = profiles.map(|profile| profile.or_else(|| config.engines.get(&config.default_engine)));
+    let mut clients: [Option<mpsc::UnboundedSender<engine::Message>>; 2] = [None, None];
+    let mut restarts = [0; 2];
+    //Clocks after each move, restored when moves are taken back
+    let mut saved_clocks = vec![(game.moves.len(), clocks.clone())];
+    //Takeback request and the number of moves when it was made
+    let mut undo: Option<(Stone, usize)> = None;
+    //Dead stones according to each bot's engine, once it has answered
+    let mut bot_marks: [Option<Vec<bool>>; 2] = [None, None];
+    let mut chat = VecDeque::<Chat>::with_capacity(CHAT_HISTORY);
+    //Unused slots are freed
+    for ((index, stone), slot) in [(0, Stone::Black), (1, Stone::White)].into_iter().zip(reserved) {
+        if bots[index] && !matches!(game.turn, Turn::Scoring | Turn::End) {
+            let profile = profiles[index].expect("No engine profile");
+            clients[index] = Some(start_engine(&pool, profile, stone, &game, time_control, slot, &sender));
+        }
+    }
+    let delay = |stone: Stone| match stone {
+        Stone::Black if bots[0] => move_delay,
+        Stone::White if bots[1] => move_delay,
+        _ => Duration::ZERO
+    };
+    //Bot games start without waiting for players
+    if bots[0] && bots[1] {
+        let _ = sender.send(Message::Start);
     }
     //Listen for requests
+    let mut ended = false;
+    //Snapshot request answered after the session stops
+    let mut snapshot = None;
     while let Some(message) = receiver.recv().await {
         match message {
             //Lobby
             Message::Join(stone, response) => {
                 //Attempt to add client
-                let success = match stone {
+                let success = game.turn != Turn::End && match stone {
                     Stone::Black => if !players[0] {
                         players[0] = true;
                         true
@@ -145,159 +449,598 @@ pub async fn session(
                     },
                     Stone::Empty => false
                 };
-                //Both players joined => Start game
                 if success {
-                    if players[0] && players[1] && timers[2].running() {
-                        //Timers
+                    let index = if stone == Stone::Black {0} else {1};
+                    if let Some(handle) = abandoned[index].take() {
                         handle.abort();
-                        timers[2].pause();
-                        let duration = timers[0].time();
-                        timers[0].resume();
-                        let sender = sender.clone();
-                        handle = tokio::spawn(async move {
-                            tokio::time::sleep(duration).await;
-                            let _ = sender.send(Message::Resign(Stone::Black));
-                        });
-                        //Engine
-                        if bots[0] {
-                            let engine = engine.clone().expect("No engine");
-                            let _ = engine.send(engine::Message::Handicap(game.handicap));
-                        }
                     }
-                    let _ = broadcast.send(Packet::new(&game, &mut timers, &players));
+                    //Both players joined => Start game
+                    if players[0] && players[1] && lobby.running() {
+                        let _ = sender.send(Message::Start);
+                    }
+                    let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
                 }
                 let _ = response.send(success);
             },
             Message::Leave(stone) => {
-                match stone {
-                    Stone::Black => players[0] = false,
-                    Stone::White => players[1] = false,
-                    Stone::Empty => ()
+                let index = match stone {
+                    Stone::Black => 0,
+                    Stone::White => 1,
+                    Stone::Empty => continue
+                };
+                players[index] = false;
+                //Players who leave a running game forfeit unless they return
+                if !lobby.running() && matches!(game.turn, Turn::Handicap | Turn::Black | Turn::White) {
+                    let sender = sender.clone();
+                    abandoned[index] = Some(tokio::spawn(async move {
+                        tokio::time::sleep(abandon_timeout).await;
+                        let _ = sender.send(Message::Abandon(stone));
+                    }));
+                }
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            Message::Abandon(stone) => {
+                let present = match stone {
+                    Stone::Black => players[0],
+                    Stone::White => players[1],
+                    Stone::Empty => true
+                };
+                if !present && game.forfeit(stone).is_ok() {
+                    let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
                 }
-                let _ = broadcast.send(Packet::new(&game, &mut timers, &players));
             },
             Message::Expire => {
-                game.turn = Turn::End;
-                let _ = broadcast.send(Packet::new(&game, &mut timers, &players));
+                if ended {
+                    break
+                }
+                let _ = game.abort();
+                lobby.pause();
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            Message::Start => if players[0] && players[1] && lobby.running() && !ended {
+                handle.abort();
+                lobby.pause();
+                if game.turn == Turn::Scoring {
+                    //Resumed during dead stone marking
+                    estimate(&config, &pool, &game, &sender);
+                    bot_dead_stones(&pool, profiles, bots, &game, &sender);
+                    let sender = sender.clone();
+                    handle = tokio::spawn(async move {
+                        tokio::time::sleep(session_timeout).await;
+                        let _ = sender.send(Message::Accept(Stone::Black));
+                        let _ = sender.send(Message::Accept(Stone::White));
+                    });
+                } else {
+                    let stone = match game.turn {
+                        Turn::White => Stone::White,
+                        _ => Stone::Black
+                    };
+                    handle = next_turn(&sender, stone, delay(stone));
+                }
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
             },
             //Game
-            Message::Handicap(stone, positions) => if game.play_handicap(stone, &positions).is_ok() {
+            //Engines playing each other may never pass
+            Message::Turn(_) if bots[0] && bots[1] && game.moves.len() >= config.limits.max_bot_moves => {
+                if game.abort().is_ok() {
+                    let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+                }
+            },
+            Message::Turn(stone) => {
+                let (index, to_move) = match game.turn {
+                    Turn::Handicap | Turn::Black => (0, Stone::Black),
+                    Turn::White => (1, Stone::White),
+                    Turn::Scoring | Turn::End => continue
+                };
+                if stone != to_move || lobby.running() {
+                    continue
+                }
                 //Timer
-                timers[0].pause();
-                timers[0].add(added_time);
+                let clock = clocks.get_mut(stone);
+                clock.resume();
+                let duration = clock.remaining();
                 handle.abort();
-                let duration = timers[1].time();
-                timers[1].resume();
                 let sender = sender.clone();
                 handle = tokio::spawn(async move {
                     tokio::time::sleep(duration).await;
-                    let _ = sender.send(Message::Resign(Stone::White));
+                    let _ = sender.send(Message::Timeout(stone));
                 });
+                //Engine
+                if let Some(engine) = &clients[index] {
+                    if game.turn == Turn::Handicap {
+                        let _ = engine.send(engine::Message::Handicap(game.handicap));
+                    } else {
+                        genmove(engine, clocks.get(stone));
+                    }
+                }
+            },
+            Message::Handicap(stone, positions) => if game.play_handicap(stone, &positions).is_ok() {
+                //Timer
+                clocks.get_mut(stone).press();
+                saved_clocks.push((game.moves.len(), clocks.clone()));
+                handle.abort();
+                handle = next_turn(&sender, Stone::White, delay(Stone::White));
                 //Broadcast
-                let _ = broadcast.send(Packet::new(&game, &mut timers, &players));
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
                 //Engine
-                if bots[1] {
-                    let engine = engine.clone().unwrap();
+                if let Some(engine) = &clients[1] {
                     for position in positions {
-                        let _ = engine.send(engine::Message::Play(stone, position as u32));
+                        let _ = engine.send(engine::Message::Play(stone, Vertex::Point(position)));
                     }
-                    let _ = engine.send(engine::Message::Genmove);
                 }
             },
             Message::Play(stone, position) => if game.play(stone, position).is_ok() {
                 //Timer
-                let (next_stone, duration) = match stone {
-                    Stone::Black => {
-                        timers[0].pause();
-                        timers[0].add(added_time);
-                        timers[1].resume();
-                        (Stone::White, timers[1].time())
-                    },
-                    Stone::White => {
-                        timers[1].pause();
-                        timers[1].add(added_time);
-                        timers[0].resume();
-                        (Stone::Black, timers[0].time())
-                    },
-                    Stone::Empty => (Stone::Empty, Duration::from_secs(0))
-                };
+                let next_stone = stone.opponent();
+                clocks.get_mut(stone).press();
+                saved_clocks.push((game.moves.len(), clocks.clone()));
                 handle.abort();
-                let sender = sender.clone();
-                handle = tokio::spawn(async move {
-                    tokio::time::sleep(duration).await;
-                    let _ = sender.send(Message::Resign(next_stone));
-                });
+                handle = next_turn(&sender, next_stone, delay(next_stone));
                 //Broadcast
-                let _ = broadcast.send(Packet::new(&game, &timers, &players));
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
                 //Engine
-                if next_stone == Stone::Black && bots[0]
-                || next_stone == Stone::White && bots[1] {
-                    let engine = engine.clone().expect("No engine");
-                    let _ = engine.send(engine::Message::Play(stone, position as u32));
-                    let _ = engine.send(engine::Message::Genmove);
+                let index = if next_stone == Stone::Black {0} else {1};
+                if let Some(engine) = &clients[index] {
+                    let _ = engine.send(engine::Message::Play(stone, Vertex::Point(position)));
                 }
             },
             Message::Pass(stone) => if game.pass(stone).is_ok() {
+                if game.turn == Turn::Scoring {
+                    //Clocks stop while dead stones are marked
+                    clocks.pause();
+                    handle.abort();
+                    //Engines are not needed for marking, free them for the scoring engine
+                    for engine in clients.iter_mut().filter_map(Option::take) {
+                        let _ = engine.send(engine::Message::Quit);
+                    }
+                    estimate(&config, &pool, &game, &sender);
+                    bot_marks = [None, None];
+                    bot_dead_stones(&pool, profiles, bots, &game, &sender);
+                    let sender = sender.clone();
+                    handle = tokio::spawn(async move {
+                        tokio::time::sleep(session_timeout).await;
+                        let _ = sender.send(Message::Accept(Stone::Black));
+                        let _ = sender.send(Message::Accept(Stone::White));
+                    });
+                    let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+                    continue
+                }
                 //Timer
-                let (next_stone, duration) = match stone {
-                    Stone::Black => {
-                        timers[0].pause();
-                        timers[0].add(added_time);
-                        timers[1].resume();
-                        (Stone::White, timers[1].time())
-                    },
-                    Stone::White => {
-                        timers[1].pause();
-                        timers[1].add(added_time);
-                        timers[0].resume();
-                        (Stone::Black, timers[0].time())
-                    },
-                    Stone::Empty => (Stone::Empty, Duration::from_secs(0))
-                };
+                let next_stone = stone.opponent();
+                clocks.get_mut(stone).press();
+                saved_clocks.push((game.moves.len(), clocks.clone()));
                 handle.abort();
-                let sender = sender.clone();
-                handle = tokio::spawn(async move {
-                    tokio::time::sleep(duration).await;
-                    let _ = sender.send(Message::Resign(next_stone));
-                });
+                handle = next_turn(&sender, next_stone, delay(next_stone));
                 //Broadcast
-                let _ = broadcast.send(Packet::new(&game, &timers, &players));
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
                 //Engine
-                if next_stone == Stone::Black && bots[0]
-                || next_stone == Stone::White && bots[1] {
-                    let engine = engine.clone().expect("No engine");
-                    let _ = engine.send(engine::Message::Genmove);
+                let index = if next_stone == Stone::Black {0} else {1};
+                if let Some(engine) = &clients[index] {
+                    let _ = engine.send(engine::Message::Play(stone, Vertex::Pass));
                 }
             },
             Message::Resign(stone) => if game.resign(stone).is_ok() {
-                let _ = broadcast.send(Packet::new(&game, &timers, &players));
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            Message::Timeout(stone) => if game.timeout(stone).is_ok() {
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            //Takebacks
+            Message::Undo(stone) => {
+                let index = match stone {
+                    Stone::Black => 0,
+                    Stone::White => 1,
+                    Stone::Empty => continue
+                };
+                let pending = undo.is_some_and(|(_, moves)| moves == game.moves.len());
+                if !takebacks || lobby.running() || pending || !game.can_undo(stone) {
+                    continue
+                }
+                undo = Some((stone, game.moves.len()));
+                //Bots agree to takebacks
+                if bots[1 - index] {
+                    let _ = sender.send(Message::UndoReply(stone.opponent(), true));
+                } else {
+                    let _ = broadcast.send(Event::UndoRequest {stone});
+                }
+            },
+            Message::UndoReply(stone, accept) => {
+                //Requests are dropped once another move is made
+                let Some((requester, moves)) = undo else {
+                    continue
+                };
+                if stone != requester.opponent() {
+                    continue
+                }
+                undo = None;
+                if moves != game.moves.len() {
+                    continue
+                }
+                if !accept {
+                    let _ = broadcast.send(Event::UndoDeclined {stone: requester});
+                    continue
+                }
+                if game.undo(requester).is_err() {
+                    continue
+                }
+                //Clocks go back to where they were after the last remaining move
+                clocks.pause();
+                saved_clocks.retain(|(moves, _)| *moves <= game.moves.len());
+                if let Some((moves, saved)) = saved_clocks.last() {
+                    if *moves == game.moves.len() {
+                        clocks = saved.clone();
+                    }
+                }
+                //Engines take back the same moves
+                let target = engine::move_count(&game);
+                for engine in clients.iter().flatten() {
+                    let _ = engine.send(engine::Message::Undo(target));
+                }
+                let next_stone = match game.turn {
+                    Turn::White => Stone::White,
+                    _ => Stone::Black
+                };
+                handle.abort();
+                handle = next_turn(&sender, next_stone, delay(next_stone));
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            Message::EngineFailure(stone, error) => {
+                let index = match stone {
+                    Stone::Black => 0,
+                    Stone::White => 1,
+                    Stone::Empty => continue
+                };
+                if ended || clients[index].is_none() {
+                    continue
+                }
+                //No free engine is not the engine's fault, wait for one without using up a restart
+                if let PoolError::Full = error {
+                    let sender = sender.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(ENGINE_RETRY).await;
+                        let _ = sender.send(Message::EngineRetry(stone));
+                    });
+                    continue
+                }
+                eprintln!("Engine {} in game {} failed: {}", names[index], id, error);
+                //Restart from the current position, then give up
+                if restarts[index] < ENGINE_RESTARTS {
+                    restarts[index] += 1;
+                    let profile = profiles[index].expect("No engine profile");
+                    clients[index] = Some(start_engine(&pool, profile, stone, &game, time_control, None, &sender));
+                    let _ = sender.send(Message::Turn(stone));
+                } else if game.engine_failure(stone).is_ok() {
+                    let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+                }
+            },
+            Message::EngineRetry(stone) => {
+                let index = match stone {
+                    Stone::Black => 0,
+                    Stone::White => 1,
+                    Stone::Empty => continue
+                };
+                if ended || clients[index].is_none() {
+                    continue
+                }
+                let profile = profiles[index].expect("No engine profile");
+                clients[index] = Some(start_engine(&pool, profile, stone, &game, time_control, None, &sender));
+                let _ = sender.send(Message::Turn(stone));
+            },
+            //Scoring
+            Message::Mark(stone, position) => if game.mark(stone, position).is_ok() {
+                accept_bots(&mut game, &bot_marks);
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            Message::Accept(stone) => if game.accept(stone).is_ok() {
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            Message::Estimate(dead, result) => if let Ok(marked) = game.suggest(&dead, result) {
+                accept_bots(&mut game, &bot_marks);
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+                if marked {
+                    let _ = broadcast.send(Event::DeadSuggested);
+                }
+            },
+            Message::BotDead(stone, dead) => if game.turn == Turn::Scoring {
+                let mut marks = vec![false; game.board.len()];
+                for pos in dead {
+                    if pos < marks.len() && game.board[pos] != Stone::Empty {
+                        marks[pos] = true;
+                    }
+                }
+                match stone {
+                    Stone::Black => bot_marks[0] = Some(marks),
+                    Stone::White => bot_marks[1] = Some(marks),
+                    Stone::Empty => continue
+                }
+                accept_bots(&mut game, &bot_marks);
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            //Hints
+            Message::AllowHints(stone, allow) => {
+                match stone {
+                    Stone::Black => hints[0] = allow,
+                    Stone::White => hints[1] = allow,
+                    Stone::Empty => continue
+                }
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
+            },
+            Message::Hint(sender) => {
+                //Finished games can always be reviewed
+                let allowed = ended || !rated && hints[0] && hints[1];
+                let _ = sender.send(if allowed {Ok(game.clone())} else {Err(AnalysisError::Disabled)});
+            },
+            //Chat
+            Message::Chat(stone, channel, text) => {
+                //Spectators can't write to the players
+                if text.is_empty() || stone == Stone::Empty && channel == Channel::Players {
+                    continue
+                }
+                let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                let message = Chat {channel, stone, text, time: time.as_millis() as u64};
+                if chat.len() == CHAT_HISTORY {
+                    chat.pop_front();
+                }
+                chat.push_back(message.clone());
+                let _ = broadcast.send(Event::Chat(message));
+            },
+            Message::Subscribe(sender) => {
+                let _ = sender.send((broadcast.subscribe(), chat.iter().cloned().collect()));
             },
             //Utility
             Message::Ping => {
-                let _ = broadcast.send(Packet::new(&game, &timers, &players));
+                let _ = broadcast.send(Event::Packet(Packet::new(&game, &clocks, &lobby, &players, &hints)));
             },
             Message::Packet(sender) => {
-                let _ = sender.send(Packet::new(&game, &timers, &players));
+                let _ = sender.send(Packet::new(&game, &clocks, &lobby, &players, &hints));
             },
             Message::Query(sender) => {
-                let _ = sender.send(Settings {
-                    board_size: game.board_size as u32,
-                    komi: game.komi,
-                    handicap: game.handicap,
-                    fixed_time: fixed_time.as_secs() as u32,
-                    added_time: added_time.as_secs() as u32
-                });
+                let _ = sender.send(settings(&game, time_control, &names, rated, takebacks));
+            },
+            Message::Record(sender) => {
+                let settings = settings(&game, time_control, &names, rated, takebacks);
+                let _ = sender.send(sgf::write(&game, &settings));
+            },
+            Message::Snapshot(sender) => {
+                //Finished games are not resumed
+                if ended {
+                    let _ = broadcast.send(Event::Shutdown);
+                    let _ = sender.send(None);
+                    break
+                }
+                clocks.pause();
+                writer.save(&game);
+                let _ = broadcast.send(Event::Shutdown);
+                //Sent once the game is written
+                snapshot = Some((sender, Snapshot {
+                    id,
+                    engines,
+                    clocks: clocks.clone(),
+                    move_delay,
+                    rated,
+                    takebacks
+                }));
+                break
             }
         }
-        if game.turn == Turn::End {
-            break
+        //Persist changes
+        if stored != (game.moves.len(), game.result, game.dead.clone()) {
+            writer.save(&game);
+            stored = (game.moves.len(), game.result, game.dead.clone());
+        }
+        //Keep finished games available until the session expires
+        if game.turn == Turn::End && !ended {
+            ended = true;
+            clocks.pause();
+            for handle in abandoned.iter_mut().filter_map(Option::take) {
+                handle.abort();
+            }
+            for engine in clients.iter_mut().filter_map(Option::take) {
+                let _ = engine.send(engine::Message::Quit);
+            }
+            handle.abort();
+            let sender = sender.clone();
+            handle = tokio::spawn(async move {
+                tokio::time::sleep(session_timeout).await;
+                let _ = sender.send(Message::Expire);
+            });
         }
     }
     //Remove session
-    if let Some(engine) = engine {
+    handle.abort();
+    for handle in abandoned.iter_mut().filter_map(Option::take) {
+        handle.abort();
+    }
+    for engine in clients.iter_mut().filter_map(Option::take) {
         let _ = engine.send(engine::Message::Quit);
     }
-    handle.abort();
+    writer.close().await;
+    if let Some((sender, snapshot)) = snapshot {
+        let _ = sender.send(Some(snapshot));
+    }
     let mut sessions = sessions.lock().unwrap();
     sessions.remove(&id);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::EngineProfile,
+        model::{Placement, Ruleset}
+    };
+    //Engine run by sh, reading "id command arguments" lines
+    fn stub(script: &str) -> EngineProfile {
+        EngineProfile {
+            name: "stub".to_string(),
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            board_sizes: Vec::new(),
+            strength: String::new(),
+            gnugo_rules: false,
+            timeout: 5
+        }
+    }
+    //Plays along the first row as Black and the last row as White, never passing
+    const PLAYER: &str = "n=0; while read id command color; do
+        if [ \"$command\" = genmove ]; then
+            set -- A B C D E F G H J; shift $n; n=$((n + 1))
+            if [ \"$color\" = black ]; then row=1; else row=9; fi
+            printf '=%s %s%s\\n\\n' \"$id\" \"$1\" \"$row\"
+        else
+            printf '=%s\\n\\n' \"$id\"
+        fi
+    done";
+    fn with_engine(profile: EngineProfile) -> Config {
+        let mut config = Config::default();
+        config.engines.insert("stub".to_string(), profile);
+        config
+    }
+    //Events until the game ends
+    async fn result(events: &mut broadcast::Receiver<Event>) -> Packet {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(10), events.recv()).await {
+                Ok(Ok(Event::Packet(packet))) if packet.result.is_some() => return packet,
+                Ok(Ok(_)) => (),
+                result => panic!("Game did not end: {:?}", result.map(|result| result.err()))
+            }
+        }
+    }
+    //Session for a 9x9 game, with engines from the given configuration
+    fn start(
+        config: Config,
+        engines: [Option<String>; 2],
+        pool: Arc<Pool>
+    ) -> (mpsc::UnboundedSender<Message>, broadcast::Sender<Event>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (broadcast, _) = broadcast::channel(32);
+        let sessions = Arc::new(Mutex::new(Sessions::new()));
+        sessions.lock().unwrap().insert(1, (sender.clone(), broadcast.clone()));
+        tokio::spawn(session(
+            1,
+            sessions,
+            sender.clone(),
+            receiver,
+            broadcast.clone(),
+            Game::new(9, 6.5, 1, Placement::Free, Ruleset::Chinese).unwrap(),
+            engines,
+            [None, None],
+            Clocks::new(TimeControl::Absolute{main: 600}),
+            Duration::ZERO,
+            false,
+            false,
+            Arc::new(config),
+            Arc::new(Storage::memory().unwrap()),
+            pool
+        ));
+        (sender, broadcast)
+    }
+    async fn join(session: &mpsc::UnboundedSender<Message>, stone: Stone) {
+        let (sender, receiver) = oneshot::channel();
+        session.send(Message::Join(stone, sender)).unwrap();
+        assert!(receiver.await.unwrap());
+    }
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (session, broadcast) = start(Config::default(), [None, None], Arc::new(Pool::new(2, Duration::ZERO)));
+        let mut events = broadcast.subscribe();
+        drop(broadcast);
+        join(&session, Stone::Black).await;
+        join(&session, Stone::White).await;
+        session.send(Message::Turn(Stone::Black)).unwrap();
+        let (sender, receiver) = oneshot::channel();
+        session.send(Message::Snapshot(sender)).unwrap();
+        let snapshot = receiver.await.unwrap().expect("No snapshot");
+        assert_eq!(snapshot.id, 1);
+        //Clocks are paused, with Black's time already running
+        let black = snapshot.clocks.get(Stone::Black);
+        assert!(!black.running());
+        assert!(!snapshot.clocks.get(Stone::White).running());
+        assert!(black.time() < Duration::from_secs(600));
+        //Clients are told, then the session stops
+        let mut shutdown = false;
+        while let Ok(event) = events.recv().await {
+            shutdown |= matches!(event, Event::Shutdown);
+        }
+        assert!(shutdown);
+        assert!(session.send(Message::Ping).is_err());
+    }
+    #[tokio::test]
+    async fn test_bot_moves() {
+        let mut config = with_engine(stub(PLAYER));
+        config.limits.max_bot_moves = 6;
+        let engines = [Some("stub".to_string()), Some("stub".to_string())];
+        let (_session, broadcast) = start(config, engines, Arc::new(Pool::new(2, Duration::ZERO)));
+        let mut events = broadcast.subscribe();
+        let packet = result(&mut events).await;
+        assert_eq!(packet.result, Some(GameResult::Aborted));
+        assert_eq!(packet.board.iter().filter(|&&stone| stone == 1).count(), 3);
+        assert_eq!(packet.board.iter().filter(|&&stone| stone == 2).count(), 3);
+    }
+    #[tokio::test]
+    async fn test_engine_crash() {
+        let config = with_engine(stub("exit 1"));
+        let (_session, broadcast) = start(config, [Some("stub".to_string()), None], Arc::new(Pool::new(2, Duration::ZERO)));
+        let mut events = broadcast.subscribe();
+        let packet = result(&mut events).await;
+        assert_eq!(packet.result, Some(GameResult::EngineFailure {stone: Stone::Black}));
+    }
+    #[tokio::test]
+    async fn test_engine_full() {
+        let config = with_engine(stub(PLAYER));
+        //The only engine is busy for longer than the restarts would last
+        let pool = Arc::new(Pool::new(1, Duration::ZERO));
+        let busy = pool.checkout(&stub("cat > /dev/null"), Ruleset::Chinese).await.unwrap();
+        let (session, broadcast) = start(config, [Some("stub".to_string()), None], pool.clone());
+        let mut events = broadcast.subscribe();
+        join(&session, Stone::White).await;
+        tokio::time::sleep(ENGINE_RETRY * (ENGINE_RESTARTS + 2)).await;
+        pool.checkin(busy);
+        //Black plays once an engine is free
+        loop {
+            match tokio::time::timeout(Duration::from_secs(10), events.recv()).await {
+                Ok(Ok(Event::Packet(packet))) => {
+                    assert_eq!(packet.result, None);
+                    if packet.board.contains(&1) {
+                        break
+                    }
+                },
+                Ok(Ok(_)) => (),
+                result => panic!("Engine did not play: {:?}", result.map(|result| result.err()))
+            }
+        }
+    }
+    #[test]
+    fn test_public() {
+        let chat = |stone, channel| Event::Chat(Chat {channel, stone, text: "Hi".to_string(), time: 0});
+        assert!(!chat(Stone::Black, Channel::Players).public());
+        assert!(chat(Stone::White, Channel::Spectators).public());
+        assert!(chat(Stone::Empty, Channel::Spectators).public());
+        assert!(Event::Shutdown.public());
+        assert!(Event::UndoRequest {stone: Stone::Black}.public());
+    }
+    #[tokio::test]
+    async fn test_chat() {
+        let (session, _broadcast) = start(Config::default(), [None, None], Arc::new(Pool::new(1, Duration::ZERO)));
+        //Spectators can't write to the players
+        session.send(Message::Chat(Stone::Empty, Channel::Players, "Hidden".to_string())).unwrap();
+        for i in 0..60 {
+            let (stone, channel) = if i % 2 == 0 {
+                (Stone::Black, Channel::Players)
+            } else {
+                (Stone::Empty, Channel::Spectators)
+            };
+            session.send(Message::Chat(stone, channel, i.to_string())).unwrap();
+        }
+        let (sender, receiver) = oneshot::channel();
+        session.send(Message::Subscribe(sender)).unwrap();
+        let (_events, history) = receiver.await.unwrap();
+        //The last 50 messages, oldest first
+        let texts: Vec<String> = history.iter().map(|chat| chat.text.clone()).collect();
+        assert_eq!(texts, (10..60).map(|i| i.to_string()).collect::<Vec<_>>());
+        //Player chat is replayed to players only
+        let public: Vec<bool> = history.into_iter().map(|chat| Event::Chat(chat).public()).collect();
+        assert_eq!(public.iter().filter(|&&public| public).count(), 25);
+        assert_eq!(public[..2], [false, true]);
+    }
+}
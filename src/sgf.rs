@@ -0,0 +1,435 @@
+use crate::{
+    model::{Action, Game, GameError, Placement, Ruleset, Settings, Stone, MAX_BOARD_SIZE},
+    timer::TimeControl,
+    coord::{Coord, CoordError}
+};
+use std::{
+    fmt,
+    error,
+    iter::Peekable,
+    str::Chars
+};
+
+pub enum SgfError {
+    Syntax,
+    Property(String),
+    Unsupported(String),
+    Settings(GameError),
+    Move(usize),
+    //Games with positions off their board can't be written
+    Position(CoordError)
+}
+
+impl fmt::Display for SgfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Syntax => write!(f, "Malformed SGF record"),
+            Self::Property(id) => write!(f, "Invalid value for property {}", id),
+            Self::Unsupported(id) => write!(f, "Unsupported property {}", id),
+            Self::Settings(error) => write!(f, "Invalid game settings: {}", error),
+            Self::Move(number) => write!(f, "Illegal move {}", number),
+            Self::Position(error) => write!(f, "Invalid position: {}", error)
+        }
+    }
+}
+
+impl fmt::Debug for SgfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for SgfError {}
+
+type Node = Vec<(String, Vec<String>)>;
+
+fn point(index: usize, width: usize, height: usize) -> Result<String, SgfError> {
+    Coord::from_index(index, width, height).map(Coord::sgf).map_err(SgfError::Position)
+}
+
+fn index(value: &str, width: usize, height: usize) -> Option<usize> {
+    Coord::parse_sgf(value, width, height).ok().map(Coord::index)
+}
+
+fn color(stone: Stone) -> &'static str {
+    match stone {
+        Stone::Black => "B",
+        Stone::White => "W",
+        Stone::Empty => ""
+    }
+}
+
+fn rules(ruleset: Ruleset) -> &'static str {
+    match ruleset {
+        Ruleset::Chinese => "Chinese",
+        Ruleset::Japanese => "Japanese",
+        Ruleset::Aga => "AGA",
+        Ruleset::NewZealand => "NZ",
+        Ruleset::TrompTaylor => "Tromp-Taylor"
+    }
+}
+
+fn overtime(time_control: TimeControl) -> Option<String> {
+    match time_control {
+        TimeControl::Absolute{..} => None,
+        TimeControl::Fischer{increment, cap: None, ..} => Some(format!("{} fischer", increment)),
+        TimeControl::Fischer{increment, cap: Some(cap), ..} => Some(format!("{} fischer (cap {})", increment, cap)),
+        TimeControl::Byoyomi{periods, period, ..} => Some(format!("{}x{} byo-yomi", periods, period)),
+        TimeControl::Canadian{stones, period, ..} => Some(format!("{}/{} canadian", stones, period))
+    }
+}
+
+//Escape SGF text values
+fn text(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(']', "\\]")
+}
+
+pub fn write(game: &Game, settings: &Settings) -> Result<String, SgfError> {
+    let mut record = String::from("(;FF[4]GM[1]CA[UTF-8]AP[casual-go]");
+    //Settings
+    record += &format!("RU[{}]", rules(game.ruleset));
+    //Rectangular boards as width:height
+    if game.width == game.height {
+        record += &format!("SZ[{}]", game.width);
+    } else {
+        record += &format!("SZ[{}:{}]", game.width, game.height);
+    }
+    record += &format!("KM[{}]", game.komi);
+    if game.handicap > 1 {
+        record += &format!("HA[{}]", game.handicap);
+    }
+    record += &format!("TM[{}]", settings.time_control.main());
+    if let Some(overtime) = overtime(settings.time_control) {
+        record += &format!("OT[{}]", overtime);
+    }
+    for (property, name) in [("PB", &settings.black_player), ("PW", &settings.white_player)] {
+        if !name.is_empty() {
+            record += &format!("{}[{}]", property, text(name));
+        }
+    }
+    if let Some(result) = game.result {
+        record += &format!("RE[{}]", result);
+    }
+    //Handicap setup
+    for entry in &game.moves {
+        if let Action::Handicap(positions) = &entry.action {
+            record += "AB";
+            for &pos in positions {
+                record += &format!("[{}]", point(pos, game.width, game.height)?);
+            }
+        }
+    }
+    record += "\n";
+    //Moves
+    for entry in &game.moves {
+        match entry.action {
+            Action::Play(pos) => {
+                record += &format!(";{}[{}]", color(entry.stone), point(pos, game.width, game.height)?);
+            },
+            Action::Pass => {
+                record += &format!(";{}[]", color(entry.stone));
+            },
+            Action::Handicap(_) | Action::Resign => ()
+        }
+    }
+    record += ")\n";
+    Ok(record)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn node(chars: &mut Peekable<Chars>) -> Result<Node, SgfError> {
+    let mut node = Node::new();
+    loop {
+        skip_whitespace(chars);
+        match chars.peek() {
+            Some(c) if c.is_ascii_uppercase() => {
+                //Identifier
+                let mut id = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+                    //Lowercase letters are ignored (FF[3])
+                    if c.is_ascii_uppercase() {
+                        id.push(c);
+                    }
+                }
+                //Values
+                let mut values = Vec::new();
+                skip_whitespace(chars);
+                while chars.next_if_eq(&'[').is_some() {
+                    let mut value = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\\') => value.push(chars.next().ok_or(SgfError::Syntax)?),
+                            Some(']') => break,
+                            Some(c) => value.push(c),
+                            None => return Err(SgfError::Syntax)
+                        }
+                    }
+                    values.push(value);
+                    skip_whitespace(chars);
+                }
+                if values.is_empty() {
+                    return Err(SgfError::Syntax)
+                }
+                node.push((id, values));
+            },
+            _ => return Ok(node)
+        }
+    }
+}
+
+//Collects the nodes of the main line, skipping other variations
+fn game_tree(chars: &mut Peekable<Chars>, nodes: &mut Vec<Node>) -> Result<(), SgfError> {
+    skip_whitespace(chars);
+    if chars.next() != Some('(') {
+        return Err(SgfError::Syntax)
+    }
+    //Open trees, with whether each is on the main line and has variations
+    //Kept on a stack so deeply nested records can't overflow the call stack
+    let mut trees = vec![(true, false)];
+    while let Some(&(main, branched)) = trees.last() {
+        skip_whitespace(chars);
+        match chars.next() {
+            //Nodes only come before the variations
+            Some(';') if !branched => {
+                let node = node(chars)?;
+                if main {
+                    nodes.push(node);
+                }
+            },
+            //Only the first variation continues the main line
+            Some('(') => {
+                trees.last_mut().expect("No open tree").1 = true;
+                trees.push((main && !branched, false));
+            },
+            Some(')') => {
+                trees.pop();
+            },
+            _ => return Err(SgfError::Syntax)
+        }
+    }
+    Ok(())
+}
+
+fn values<'a>(node: &'a Node, id: &str) -> Option<&'a [String]> {
+    node.iter()
+        .find(|(key, _)| key == id)
+        .map(|(_, values)| values.as_slice())
+}
+
+fn points(values: &[String], width: usize, height: usize, id: &str) -> Result<Vec<usize>, SgfError> {
+    let mut result = Vec::new();
+    for value in values {
+        //Compressed point lists
+        let (from, to) = value.split_once(':').unwrap_or((value, value));
+        let error = || SgfError::Property(id.to_string());
+        let from = index(from, width, height).ok_or_else(error)?;
+        let to = index(to, width, height).ok_or_else(error)?;
+        for y in from / width..=to / width {
+            for x in from % width..=to % width {
+                result.push(width * y + x);
+            }
+        }
+    }
+    Ok(result)
+}
+
+pub fn read(record: &str, limit: Option<usize>) -> Result<Game, SgfError> {
+    let mut nodes = Vec::new();
+    game_tree(&mut record.chars().peekable(), &mut nodes)?;
+    let root = nodes.first().ok_or(SgfError::Syntax)?;
+    //Settings
+    let (width, height) = match values(root, "SZ") {
+        Some(values) => {
+            let size = |value: &str| value.trim().parse::<usize>().ok()
+                .filter(|size| (2..=MAX_BOARD_SIZE).contains(size))
+                .ok_or_else(|| SgfError::Unsupported("SZ".into()));
+            match values[0].split_once(':') {
+                Some((width, height)) => (size(width)?, size(height)?),
+                None => (size(&values[0])?, size(&values[0])?)
+            }
+        },
+        None => (19, 19)
+    };
+    let komi = match values(root, "KM") {
+        Some(values) => values[0].trim().parse::<f32>()
+            .map_err(|_| SgfError::Property("KM".into()))?,
+        None => 0.0
+    };
+    let ruleset = match values(root, "RU").map(|values| values[0].trim().to_lowercase()) {
+        Some(rules) => match rules.as_str() {
+            "japanese" | "korean" => Ruleset::Japanese,
+            "aga" => Ruleset::Aga,
+            "nz" | "new zealand" => Ruleset::NewZealand,
+            "tromp-taylor" => Ruleset::TrompTaylor,
+            _ => Ruleset::Chinese
+        },
+        None => Ruleset::Chinese
+    };
+    let stones = match values(root, "AB") {
+        Some(values) => points(values, width, height, "AB")?,
+        None => Vec::new()
+    };
+    //A single AB stone is no handicap, it stands for Black's first move
+    let handicap = if stones.len() >= 2 {stones.len() as u32} else {1};
+    let mut game = match Game::rectangular(width, height, komi, handicap, Placement::Free, ruleset) {
+        Ok(game) => game,
+        Err(GameError::Creation) => return Err(SgfError::Property("KM".into())),
+        Err(error) => return Err(SgfError::Settings(error))
+    };
+    let setup = match stones.len() {
+        0 => Ok(()),
+        1 => game.play(Stone::Black, stones[0]),
+        _ => game.play_handicap(Stone::Black, &stones)
+    };
+    setup.map_err(|_| SgfError::Property("AB".into()))?;
+    //Moves
+    let mut count = 0;
+    for (i, node) in nodes.iter().enumerate() {
+        for (id, values) in node {
+            let stone = match id.as_str() {
+                "B" => Stone::Black,
+                "W" => Stone::White,
+                "AW" | "AE" => return Err(SgfError::Unsupported(id.clone())),
+                "AB" if i > 0 => return Err(SgfError::Unsupported(id.clone())),
+                _ => continue
+            };
+            if limit.is_some_and(|limit| count >= limit) {
+                return Ok(game)
+            }
+            count += 1;
+            let value = values[0].trim();
+            let result = if value.is_empty() || (value == "tt" && width <= 19 && height <= 19) {
+                game.pass(stone)
+            } else {
+                let pos = index(value, width, height).ok_or(SgfError::Move(count))?;
+                game.play(stone, pos)
+            };
+            result.map_err(|_| SgfError::Move(count))?;
+        }
+    }
+    Ok(game)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Turn;
+    fn settings() -> Settings {
+        Settings {
+            board_size: 9,
+            board_height: None,
+            komi: 7.0,
+            handicap: 2,
+            placement: Placement::Free,
+            ruleset: Ruleset::Chinese,
+            time_control: TimeControl::Fischer{main: 600, increment: 10, cap: None},
+            black_player: "Human".to_string(),
+            white_player: "GNU Go [1]".to_string(),
+            rated: false,
+            takebacks: false
+        }
+    }
+    fn actions(game: &Game) -> Vec<Action> {
+        game.moves.iter().map(|x| x.action.clone()).collect()
+    }
+    #[test]
+    fn test_point() {
+        assert_eq!(point(0, 19, 19).unwrap(), "aa");
+        assert_eq!(point(18, 19, 19).unwrap(), "sa");
+        assert_eq!(point(360, 19, 19).unwrap(), "ss");
+        assert_eq!(point(40, 9, 9).unwrap(), "ee");
+        assert_eq!(point(624, 25, 25).unwrap(), "yy");
+        assert_eq!(point(14, 5, 3).unwrap(), "ec");
+        assert!(matches!(point(15, 5, 3), Err(SgfError::Position(_))));
+    }
+    #[test]
+    fn test_write() {
+        let mut game = Game::new(9, 7.0, 2, Placement::Free, Ruleset::Chinese).unwrap();
+        game.play_handicap(Stone::Black, &[20, 60]).unwrap();
+        game.play(Stone::White, 40).unwrap();
+        game.pass(Stone::Black).unwrap();
+        game.resign(Stone::White).unwrap();
+        assert_eq!(
+            write(&game, &settings()).unwrap(),
+            "(;FF[4]GM[1]CA[UTF-8]AP[casual-go]RU[Chinese]SZ[9]KM[7]HA[2]TM[600]OT[10 fischer]PB[Human]PW[GNU Go [1\\]]RE[B+R]AB[cc][gg]\n;W[ee];B[])\n"
+        );
+    }
+    #[test]
+    fn test_write_in_progress() {
+        let mut game = Game::new(9, 7.0, 1, Placement::Free, Ruleset::Chinese).unwrap();
+        game.play(Stone::Black, 0).unwrap();
+        let record = write(&game, &settings()).unwrap();
+        assert!(!record.contains("RE["));
+        assert!(record.ends_with(";B[aa])\n"));
+    }
+    #[test]
+    fn test_read() {
+        let record = "(;FF[4]RU[Japanese]SZ[9]KM[6.5]AB[cc][gg]\n;W[ee](;B[]\n;W[dd])(;B[aa]))";
+        let game = read(record, None).unwrap();
+        assert_eq!(game.komi, 6.5);
+        assert_eq!(game.handicap, 2);
+        assert_eq!(game.ruleset, Ruleset::Japanese);
+        assert_eq!(actions(&game), vec![
+            Action::Handicap(vec![20, 60]),
+            Action::Play(40),
+            Action::Pass,
+            Action::Play(30)
+        ]);
+        assert_eq!(game.turn, Turn::Black);
+        //Partial replay
+        let game = read(record, Some(1)).unwrap();
+        assert_eq!(game.moves.len(), 2);
+        assert_eq!(game.turn, Turn::Black);
+    }
+    #[test]
+    fn test_read_setup() {
+        //One AB stone is not a handicap
+        let game = read("(;SZ[9]AB[ee];W[cc])", None).unwrap();
+        assert_eq!(game.handicap, 1);
+        assert_eq!(game.board[40], Stone::Black);
+        assert_eq!(actions(&game), vec![Action::Play(40), Action::Play(20)]);
+        assert_eq!(game.turn, Turn::Black);
+        //Variations nested deeper than the call stack
+        let record = format!("{};SZ[9];B[aa]{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(actions(&read(&record, None).unwrap()), vec![Action::Play(0)]);
+        assert!(matches!(read(&"(".repeat(100_000), None), Err(SgfError::Syntax)));
+    }
+    #[test]
+    fn test_round_trip() {
+        let mut game = Game::new(9, 7.0, 2, Placement::Free, Ruleset::Chinese).unwrap();
+        game.play_handicap(Stone::Black, &[20, 60]).unwrap();
+        game.play(Stone::White, 40).unwrap();
+        game.play(Stone::Black, 41).unwrap();
+        let record = write(&game, &settings()).unwrap();
+        assert_eq!(actions(&read(&record, None).unwrap()), actions(&game));
+        //Rectangular and largest boards
+        for (width, height) in [(5, 3), (25, 25)] {
+            let mut game = Game::rectangular(width, height, 7.0, 1, Placement::Free, Ruleset::Chinese).unwrap();
+            game.play(Stone::Black, width * height - 1).unwrap();
+            game.pass(Stone::White).unwrap();
+            let record = write(&game, &settings()).unwrap();
+            let read = read(&record, None).unwrap();
+            assert_eq!((read.width, read.height), (width, height));
+            assert_eq!(actions(&read), actions(&game));
+        }
+        assert!(write(&Game::rectangular(5, 3, 0.0, 1, Placement::Free, Ruleset::Chinese).unwrap(), &settings()).unwrap()
+            .contains("SZ[5:3]"));
+    }
+    #[test]
+    fn test_read_errors() {
+        assert!(matches!(read("(;SZ[9];B[aa]", None), Err(SgfError::Syntax)));
+        let size = |record: &str| matches!(read(record, None), Err(SgfError::Unsupported(id)) if id == "SZ");
+        assert!(size("(;SZ[42])"));
+        assert!(size("(;SZ[1])"));
+        assert!(size("(;SZ[9:26])"));
+        assert!(size("(;SZ[99999999999:99999999999])"));
+        assert!(size("(;SZ[18446744073709551615])"));
+        assert!(matches!(read("(;SZ[9]KM[6.3])", None), Err(SgfError::Property(_))));
+        assert!(matches!(read("(;SZ[9]AW[aa])", None), Err(SgfError::Unsupported(_))));
+        assert!(matches!(read("(;SZ[9];B[aa];B[bb])", None), Err(SgfError::Move(2))));
+        assert!(matches!(read("(;SZ[9];B[aa];W[aa])", None), Err(SgfError::Move(2))));
+    }
+}
@@ -0,0 +1,480 @@
+use crate::{
+    model::{Action, Game, GameError, GameResult, Move, Settings, Stone, Turn},
+    session::Snapshot
+};
+use rusqlite::{Connection, OptionalExtension, params};
+use tokio::{sync::mpsc, task::{self, JoinHandle}};
+use std::{
+    fmt,
+    error,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+pub enum StorageError {
+    Database(rusqlite::Error),
+    Format(serde_json::Error)
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Database(error) => write!(f, "Database error: {}", error),
+            StorageError::Format(error) => write!(f, "Malformed record: {}", error)
+        }
+    }
+}
+
+impl fmt::Debug for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(value: rusqlite::Error) -> Self {
+        StorageError::Database(value)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(value: serde_json::Error) -> Self {
+        StorageError::Format(value)
+    }
+}
+
+/*
+    Schema
+    games: One row per session, settings and result as JSON
+    moves: Move list, numbered from 0 in play order
+    snapshots: Sessions running at shutdown, to be resumed on startup
+    Times are milliseconds since the Unix epoch
+*/
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS games (
+        id INTEGER PRIMARY KEY,
+        settings TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        updated INTEGER NOT NULL,
+        result TEXT,
+        dead TEXT NOT NULL DEFAULT '[]'
+    );
+    CREATE TABLE IF NOT EXISTS moves (
+        game INTEGER NOT NULL REFERENCES games(id),
+        number INTEGER NOT NULL,
+        stone TEXT NOT NULL,
+        action TEXT NOT NULL,
+        captures TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        PRIMARY KEY (game, number)
+    );
+    CREATE TABLE IF NOT EXISTS snapshots (
+        id INTEGER PRIMARY KEY REFERENCES games(id),
+        data TEXT NOT NULL
+    );
+";
+
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+fn time(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+//A stored game
+pub struct Record {
+    pub id: usize,
+    pub settings: Settings,
+    pub created: SystemTime,
+    pub updated: SystemTime,
+    pub result: Option<GameResult>,
+    pub dead: Vec<bool>,
+    pub moves: Vec<Move>
+}
+
+impl Record {
+    //Rebuild the game by replaying its moves
+    pub fn game(&self) -> Result<Game, GameError> {
+        let settings = &self.settings;
+        let mut game = Game::rectangular(
+            settings.board_size as usize,
+            settings.height() as usize,
+            settings.komi,
+            settings.handicap,
+            settings.placement,
+            settings.ruleset
+        )?;
+        //Fixed handicaps are placed by Game::new
+        for entry in self.moves.iter().skip(game.moves.len()) {
+            match &entry.action {
+                Action::Handicap(positions) => game.play_handicap(entry.stone, positions)?,
+                Action::Play(position) => game.play(entry.stone, *position)?,
+                Action::Pass => game.pass(entry.stone)?,
+                Action::Resign => game.resign(entry.stone)?
+            }
+        }
+        for (entry, stored) in game.moves.iter_mut().zip(&self.moves) {
+            entry.time = stored.time;
+        }
+        if self.dead.len() == game.dead.len() {
+            game.dead = self.dead.clone();
+        }
+        if let Some(result) = self.result {
+            game.result = Some(result);
+            game.turn = Turn::End;
+        }
+        Ok(game)
+    }
+}
+
+pub struct Storage {
+    connection: Mutex<Connection>
+}
+
+impl Storage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Storage, StorageError> {
+        Storage::init(Connection::open(path)?)
+    }
+    pub fn memory() -> Result<Storage, StorageError> {
+        Storage::init(Connection::open_in_memory()?)
+    }
+    fn init(connection: Connection) -> Result<Storage, StorageError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Storage {
+            connection: Mutex::new(connection)
+        })
+    }
+    //Resumed games keep their creation time
+    pub fn create(&self, id: usize, settings: &Settings) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let now = millis(SystemTime::now());
+        connection.execute(
+            "INSERT INTO games (id, settings, created, updated) VALUES (?1, ?2, ?3, ?3)
+                ON CONFLICT(id) DO UPDATE SET settings = excluded.settings, updated = excluded.updated",
+            params![id as i64, serde_json::to_string(settings)?, now]
+        )?;
+        Ok(())
+    }
+    //Write moves not yet stored, the dead stones and the result
+    pub fn save(&self, id: usize, game: &Game) -> Result<(), StorageError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        //Moves already stored, up to the first one that was taken back and replaced
+        let stored: Vec<(String, String, i64)> = transaction.prepare(
+            "SELECT stone, action, time FROM moves WHERE game = ?1 ORDER BY number"
+        )?.query_map(params![id as i64], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        let mut kept = 0;
+        for ((stone, action, time), entry) in stored.iter().zip(&game.moves) {
+            let same = stone == <&str>::from(entry.stone)
+                && *action == serde_json::to_string(&entry.action)?
+                && *time == millis(entry.time);
+            if !same {
+                break
+            }
+            kept += 1;
+        }
+        //Drop moves that are no longer part of the game
+        transaction.execute(
+            "DELETE FROM moves WHERE game = ?1 AND number >= ?2",
+            params![id as i64, kept as i64]
+        )?;
+        for (number, entry) in game.moves.iter().enumerate().skip(kept) {
+            let stone: &str = entry.stone.into();
+            transaction.execute(
+                "INSERT INTO moves (game, number, stone, action, captures, time) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    id as i64,
+                    number as i64,
+                    stone,
+                    serde_json::to_string(&entry.action)?,
+                    serde_json::to_string(&entry.captures)?,
+                    millis(entry.time)
+                ]
+            )?;
+        }
+        let result = match game.result {
+            Some(result) => Some(serde_json::to_string(&result)?),
+            None => None
+        };
+        transaction.execute(
+            "UPDATE games SET updated = ?2, result = ?3, dead = ?4 WHERE id = ?1",
+            params![
+                id as i64,
+                millis(SystemTime::now()),
+                result,
+                serde_json::to_string(&game.dead)?
+            ]
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+    pub fn load(&self, id: usize) -> Result<Option<Record>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let row = connection.query_row(
+            "SELECT settings, created, updated, result, dead FROM games WHERE id = ?1",
+            params![id as i64],
+            |row| Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, String>(4)?
+            ))
+        ).optional()?;
+        let Some((settings, created, updated, result, dead)) = row else {
+            return Ok(None)
+        };
+        let mut statement = connection.prepare(
+            "SELECT stone, action, captures, time FROM moves WHERE game = ?1 ORDER BY number"
+        )?;
+        let rows = statement.query_map(params![id as i64], |row| Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, i64>(3)?
+        )))?;
+        let mut moves = Vec::new();
+        for row in rows {
+            let (stone, action, captures, millis) = row?;
+            moves.push(Move {
+                action: serde_json::from_str(&action)?,
+                stone: if stone == "white" {Stone::White} else {Stone::Black},
+                captures: serde_json::from_str(&captures)?,
+                time: time(millis)
+            });
+        }
+        Ok(Some(Record {
+            id,
+            settings: serde_json::from_str(&settings)?,
+            created: time(created),
+            updated: time(updated),
+            result: match result {
+                Some(result) => Some(serde_json::from_str(&result)?),
+                None => None
+            },
+            dead: serde_json::from_str(&dead)?,
+            moves
+        }))
+    }
+    pub fn save_snapshot(&self, snapshot: &Snapshot) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO snapshots (id, data) VALUES (?1, ?2)",
+            params![snapshot.id as i64, serde_json::to_string(snapshot)?]
+        )?;
+        Ok(())
+    }
+    //Remove and return all snapshots
+    pub fn take_snapshots(&self) -> Result<Vec<Snapshot>, StorageError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let mut snapshots = Vec::new();
+        {
+            let mut statement = transaction.prepare("SELECT data FROM snapshots")?;
+            let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+            for row in rows {
+                snapshots.push(serde_json::from_str(&row?)?);
+            }
+        }
+        transaction.execute("DELETE FROM snapshots", [])?;
+        transaction.commit()?;
+        Ok(snapshots)
+    }
+}
+
+/*
+    Writes for one session, made in order on a blocking thread
+    The game is created with its settings before the first save
+    Only the latest state waiting to be saved is written
+*/
+pub struct Writer {
+    sender: mpsc::UnboundedSender<Game>,
+    handle: JoinHandle<()>
+}
+
+impl Writer {
+    pub fn new(storage: Arc<Storage>, id: usize, settings: Settings) -> Writer {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Game>();
+        let handle = tokio::spawn(async move {
+            let mut settings = Some(settings);
+            while let Some(mut game) = receiver.recv().await {
+                while let Ok(next) = receiver.try_recv() {
+                    game = next;
+                }
+                let storage = storage.clone();
+                let settings = settings.take();
+                let result = task::spawn_blocking(move || {
+                    if let Some(settings) = settings {
+                        storage.create(id, &settings)?;
+                    }
+                    storage.save(id, &game)
+                }).await;
+                match result {
+                    Ok(Ok(())) => (),
+                    Ok(Err(error)) => eprintln!("Error storing game {}: {}", id, error),
+                    Err(error) => eprintln!("Error storing game {}: {}", id, error)
+                }
+            }
+        });
+        Writer {sender, handle}
+    }
+    pub fn save(&self, game: &Game) {
+        let _ = self.sender.send(game.clone());
+    }
+    //Wait for the writes still queued
+    pub async fn close(self) {
+        drop(self.sender);
+        let _ = self.handle.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::{Placement, Ruleset}, timer::{Clocks, TimeControl}};
+    fn settings() -> Settings {
+        Settings {
+            board_size: 9,
+            board_height: None,
+            komi: 6.5,
+            handicap: 1,
+            placement: Placement::Free,
+            ruleset: Ruleset::Japanese,
+            time_control: TimeControl::Absolute{main: 600},
+            black_player: "Human".to_string(),
+            white_player: "Human".to_string(),
+            rated: true,
+            takebacks: false
+        }
+    }
+    #[test]
+    fn test_round_trip() {
+        let storage = Storage::memory().unwrap();
+        let settings = settings();
+        let mut game = Game::new(9, 6.5, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        storage.create(7, &settings).unwrap();
+        game.play(Stone::Black, 40).unwrap();
+        game.play(Stone::White, 41).unwrap();
+        storage.save(7, &game).unwrap();
+        game.pass(Stone::Black).unwrap();
+        game.resign(Stone::White).unwrap();
+        storage.save(7, &game).unwrap();
+        let record = storage.load(7).unwrap().unwrap();
+        assert_eq!(record.moves.len(), 4);
+        assert_eq!(record.moves[1].action, Action::Play(41));
+        assert_eq!(record.moves[1].stone, Stone::White);
+        assert_eq!(record.result, game.result);
+        assert!(record.settings.rated);
+        let replayed = record.game().unwrap();
+        assert_eq!(replayed.board, game.board);
+        assert_eq!(replayed.turn, Turn::End);
+        assert!(storage.load(8).unwrap().is_none());
+    }
+    #[test]
+    fn test_create_again() {
+        let storage = Storage::memory().unwrap();
+        let mut game = Game::new(9, 6.5, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        storage.create(7, &settings()).unwrap();
+        game.play(Stone::Black, 40).unwrap();
+        storage.save(7, &game).unwrap();
+        let created = storage.load(7).unwrap().unwrap().created;
+        std::thread::sleep(Duration::from_millis(5));
+        //As when a session is resumed
+        let mut settings = settings();
+        settings.takebacks = true;
+        storage.create(7, &settings).unwrap();
+        let record = storage.load(7).unwrap().unwrap();
+        assert_eq!(record.created, created);
+        assert!(record.updated > created);
+        assert!(record.settings.takebacks);
+        assert_eq!(record.moves.len(), 1);
+    }
+    #[test]
+    fn test_undo() {
+        let storage = Storage::memory().unwrap();
+        let mut game = Game::new(9, 6.5, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        storage.create(7, &settings()).unwrap();
+        game.play(Stone::Black, 40).unwrap();
+        game.play(Stone::White, 41).unwrap();
+        storage.save(7, &game).unwrap();
+        //Taken back moves are removed
+        game.undo(Stone::White).unwrap();
+        storage.save(7, &game).unwrap();
+        let record = storage.load(7).unwrap().unwrap();
+        assert_eq!(record.moves.len(), 1);
+        //And replaced by the moves played instead
+        game.play(Stone::White, 42).unwrap();
+        game.play(Stone::Black, 43).unwrap();
+        storage.save(7, &game).unwrap();
+        let record = storage.load(7).unwrap().unwrap();
+        assert_eq!(record.moves.len(), 3);
+        assert_eq!(record.moves[1].action, Action::Play(42));
+        assert_eq!(record.game().unwrap().board, game.board);
+    }
+    #[test]
+    fn test_undo_replaced() {
+        let storage = Storage::memory().unwrap();
+        let mut game = Game::new(9, 6.5, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        storage.create(7, &settings()).unwrap();
+        game.play(Stone::Black, 40).unwrap();
+        game.play(Stone::White, 41).unwrap();
+        game.play(Stone::Black, 42).unwrap();
+        storage.save(7, &game).unwrap();
+        //Taken back and replayed before the next save, ending at the same move count
+        game.undo(Stone::Black).unwrap();
+        game.play(Stone::Black, 50).unwrap();
+        storage.save(7, &game).unwrap();
+        let record = storage.load(7).unwrap().unwrap();
+        let actions: Vec<Action> = record.moves.iter().map(|entry| entry.action.clone()).collect();
+        assert_eq!(actions, [Action::Play(40), Action::Play(41), Action::Play(50)]);
+        assert_eq!(record.game().unwrap().board, game.board);
+        //Both moves of a takeback with the reply
+        game.undo(Stone::White).unwrap();
+        game.play(Stone::White, 60).unwrap();
+        game.play(Stone::Black, 61).unwrap();
+        storage.save(7, &game).unwrap();
+        let record = storage.load(7).unwrap().unwrap();
+        let actions: Vec<Action> = record.moves.iter().map(|entry| entry.action.clone()).collect();
+        assert_eq!(actions, [Action::Play(40), Action::Play(60), Action::Play(61)]);
+    }
+    #[tokio::test]
+    async fn test_writer() {
+        let storage = Arc::new(Storage::memory().unwrap());
+        let writer = Writer::new(storage.clone(), 5, settings());
+        let mut game = Game::new(9, 6.5, 1, Placement::Free, Ruleset::Japanese).unwrap();
+        writer.save(&game);
+        game.play(Stone::Black, 40).unwrap();
+        writer.save(&game);
+        game.play(Stone::White, 41).unwrap();
+        writer.save(&game);
+        writer.close().await;
+        let record = storage.load(5).unwrap().unwrap();
+        assert_eq!(record.moves.len(), 2);
+        assert_eq!(record.moves[1].action, Action::Play(41));
+    }
+    #[test]
+    fn test_snapshots() {
+        let storage = Storage::memory().unwrap();
+        storage.create(3, &settings()).unwrap();
+        storage.save_snapshot(&Snapshot {
+            id: 3,
+            engines: [None, Some("gnugo".to_string())],
+            clocks: Clocks::new(TimeControl::Absolute{main: 600}),
+            move_delay: Duration::from_secs(2),
+            rated: true,
+            takebacks: true
+        }).unwrap();
+        let snapshots = storage.take_snapshots().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].id, 3);
+        assert_eq!(snapshots[0].engines, [None, Some("gnugo".to_string())]);
+        assert_eq!(snapshots[0].move_delay, Duration::from_secs(2));
+        assert!(snapshots[0].rated && snapshots[0].takebacks);
+        assert!(storage.take_snapshots().unwrap().is_empty());
+    }
+}
@@ -1,4 +1,9 @@
-use std::time::{Duration, Instant};
+use crate::model::Stone;
+use serde::{Serialize, Deserialize};
+use std::{
+    fmt,
+    time::{Duration, Instant}
+};
 
 pub struct Timer {
     time: Duration,
@@ -42,3 +47,341 @@ impl Timer {
         self.time += time;
     }
 }
+
+//All times in seconds
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "system", rename_all = "lowercase")]
+pub enum TimeControl {
+    Absolute {main: u32},
+    Fischer {main: u32, increment: u32, cap: Option<u32>},
+    Byoyomi {main: u32, periods: u32, period: u32},
+    Canadian {main: u32, stones: u32, period: u32}
+}
+
+impl TimeControl {
+    pub fn main(self) -> u32 {
+        match self {
+            TimeControl::Absolute{main}
+            | TimeControl::Fischer{main, ..}
+            | TimeControl::Byoyomi{main, ..}
+            | TimeControl::Canadian{main, ..} => main
+        }
+    }
+    //Overtime per move: Fischer increment, byo-yomi period or Canadian time per stone
+    pub fn added(self) -> u32 {
+        match self {
+            TimeControl::Absolute{..} => 0,
+            TimeControl::Fischer{increment, ..} => increment,
+            TimeControl::Byoyomi{period, ..} => period,
+            TimeControl::Canadian{stones, period, ..} => period.div_ceil(stones.max(1))
+        }
+    }
+    //Size limits are part of the server configuration
+    pub fn valid(self) -> bool {
+        match self {
+            TimeControl::Absolute{main} => main > 0,
+            TimeControl::Fischer{main, increment, cap} => {
+                main > 0 && cap.is_none_or(|cap| cap >= increment)
+            },
+            TimeControl::Byoyomi{periods, period, ..} => {
+                (1..=10).contains(&periods) && period > 0
+            },
+            TimeControl::Canadian{stones, period, ..} => {
+                (1..=50).contains(&stones) && period > 0
+            }
+        }
+    }
+}
+
+impl fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeControl::Absolute{main} => write!(f, "Absolute {}s", main),
+            TimeControl::Fischer{main, increment, cap} => {
+                write!(f, "Fischer {}s + {}s", main, increment)?;
+                if let Some(cap) = cap {
+                    write!(f, " (cap {}s)", cap)?;
+                }
+                Ok(())
+            },
+            TimeControl::Byoyomi{main, periods, period} => {
+                write!(f, "Byo-yomi {}s + {}x{}s", main, periods, period)
+            },
+            TimeControl::Canadian{main, stones, period} => {
+                write!(f, "Canadian {}s + {}/{}s", main, stones, period)
+            }
+        }
+    }
+}
+
+/*
+    Per-player clock
+    Main time runs out first, then overtime:
+    Byo-yomi: Each period resets after a move, and is lost if it expires
+    Canadian: A fixed number of stones must be played within each period
+    Stopped clocks can be serialized, running clocks are saved paused
+*/
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Clock {
+    control: TimeControl,
+    main: Duration,
+    period: Duration,
+    periods: u32, //Byo-yomi periods or Canadian stones left
+    #[serde(skip, default = "Instant::now")]
+    mark: Instant,
+    #[serde(skip)]
+    running: bool
+}
+
+impl Clock {
+    pub fn new(control: TimeControl) -> Clock {
+        let (period, periods) = match control {
+            TimeControl::Absolute{..} | TimeControl::Fischer{..} => (0, 0),
+            TimeControl::Byoyomi{periods, period, ..} => (period, periods),
+            TimeControl::Canadian{stones, period, ..} => (period, stones)
+        };
+        Clock {
+            control,
+            main: Duration::from_secs(control.main().into()),
+            period: Duration::from_secs(period.into()),
+            periods,
+            mark: Instant::now(),
+            running: false
+        }
+    }
+    //Consume time from main time, then overtime
+    fn spend(&mut self, elapsed: Duration) {
+        let rest = elapsed.saturating_sub(self.main);
+        self.main = self.main.saturating_sub(elapsed);
+        match self.control {
+            TimeControl::Absolute{..} | TimeControl::Fischer{..} => (),
+            TimeControl::Byoyomi{period, ..} => {
+                let mut rest = rest;
+                while !rest.is_zero() && self.periods > 0 {
+                    if rest < self.period {
+                        self.period -= rest;
+                        rest = Duration::ZERO;
+                    } else {
+                        rest -= self.period;
+                        self.periods -= 1;
+                        self.period = if self.periods > 0 {
+                            Duration::from_secs(period.into())
+                        } else {
+                            Duration::ZERO
+                        };
+                    }
+                }
+            },
+            TimeControl::Canadian{..} => self.period = self.period.saturating_sub(rest)
+        }
+    }
+    //Clock state as of now
+    fn current(&self) -> Clock {
+        let mut clock = self.clone();
+        if self.running {
+            clock.spend(Instant::now().duration_since(self.mark));
+            clock.running = false;
+        }
+        clock
+    }
+    pub fn running(&self) -> bool {
+        self.running
+    }
+    pub fn overtime(&self) -> bool {
+        let clock = self.current();
+        clock.main.is_zero() && clock.period > Duration::ZERO
+    }
+    //Time left in the current main time or period
+    pub fn time(&self) -> Duration {
+        let clock = self.current();
+        if clock.overtime() {
+            clock.period
+        } else {
+            clock.main
+        }
+    }
+    pub fn periods(&self) -> u32 {
+        self.current().periods
+    }
+    //Time until the flag falls
+    pub fn remaining(&self) -> Duration {
+        let clock = self.current();
+        match clock.control {
+            TimeControl::Absolute{..} | TimeControl::Fischer{..} => clock.main,
+            TimeControl::Byoyomi{period, ..} => clock.main + if clock.periods > 0 {
+                clock.period + Duration::from_secs(period.into()) * (clock.periods - 1)
+            } else {
+                Duration::ZERO
+            },
+            TimeControl::Canadian{..} => clock.main + clock.period
+        }
+    }
+    pub fn resume(&mut self) {
+        if !self.running {
+            self.mark = Instant::now();
+            self.running = true;
+        }
+    }
+    pub fn pause(&mut self) {
+        if self.running {
+            *self = self.current();
+        }
+    }
+    //Stop the clock after a move
+    pub fn press(&mut self) {
+        self.pause();
+        self.credit();
+    }
+    fn credit(&mut self) {
+        match self.control {
+            TimeControl::Absolute{..} => (),
+            TimeControl::Fischer{increment, cap, ..} => {
+                let time = self.main + Duration::from_secs(increment.into());
+                self.main = match cap {
+                    Some(cap) => time.min(self.main.max(Duration::from_secs(cap.into()))),
+                    None => time
+                };
+            },
+            TimeControl::Byoyomi{period, ..} => if self.main.is_zero() && self.periods > 0 {
+                self.period = Duration::from_secs(period.into());
+            },
+            TimeControl::Canadian{stones, period, ..} => if self.main.is_zero() && !self.period.is_zero() {
+                self.periods -= 1;
+                if self.periods == 0 {
+                    self.periods = stones;
+                    self.period = Duration::from_secs(period.into());
+                }
+            }
+        }
+    }
+    //GTP time_left arguments: seconds and stones/periods (0 in main time)
+    pub fn time_left(&self) -> (u32, u32) {
+        let clock = self.current();
+        if clock.overtime() {
+            (clock.period.as_secs() as u32, clock.periods)
+        } else {
+            (clock.main.as_secs() as u32, 0)
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Clocks {
+    black: Clock,
+    white: Clock
+}
+
+impl Clocks {
+    pub fn new(control: TimeControl) -> Clocks {
+        Clocks {
+            black: Clock::new(control),
+            white: Clock::new(control)
+        }
+    }
+    pub fn control(&self) -> TimeControl {
+        self.black.control
+    }
+    pub fn get(&self, stone: Stone) -> &Clock {
+        match stone {
+            Stone::White => &self.white,
+            _ => &self.black
+        }
+    }
+    pub fn get_mut(&mut self, stone: Stone) -> &mut Clock {
+        match stone {
+            Stone::White => &mut self.white,
+            _ => &mut self.black
+        }
+    }
+    //Stop the mover's clock and start the opponent's
+    //Returns the time until the opponent's flag falls
+    pub fn switch(&mut self, stone: Stone) -> Duration {
+        self.get_mut(stone).press();
+        let next = self.get_mut(stone.opponent());
+        next.resume();
+        next.remaining()
+    }
+    pub fn pause(&mut self) {
+        self.black.pause();
+        self.white.pause();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+    #[test]
+    fn test_fischer() {
+        let mut clock = Clock::new(TimeControl::Fischer{main: 60, increment: 10, cap: Some(75)});
+        clock.spend(secs(5));
+        clock.credit();
+        assert_eq!(clock.time(), secs(65));
+        clock.credit();
+        assert_eq!(clock.time(), secs(75));
+        clock.spend(secs(75));
+        assert_eq!(clock.remaining(), Duration::ZERO);
+    }
+    #[test]
+    fn test_byoyomi() {
+        let mut clock = Clock::new(TimeControl::Byoyomi{main: 60, periods: 3, period: 30});
+        assert_eq!(clock.remaining(), secs(150));
+        //Into the first period
+        clock.spend(secs(70));
+        clock.credit();
+        assert!(clock.overtime());
+        assert_eq!(clock.time(), secs(30));
+        assert_eq!(clock.periods(), 3);
+        //Overrun one period
+        clock.spend(secs(45));
+        assert_eq!(clock.periods(), 2);
+        assert_eq!(clock.time(), secs(15));
+        clock.credit();
+        assert_eq!(clock.time(), secs(30));
+        assert_eq!(clock.remaining(), secs(60));
+        //Flag
+        clock.spend(secs(60));
+        assert_eq!(clock.periods(), 0);
+        assert_eq!(clock.remaining(), Duration::ZERO);
+    }
+    #[test]
+    fn test_canadian() {
+        let mut clock = Clock::new(TimeControl::Canadian{main: 0, stones: 2, period: 60});
+        assert!(clock.overtime());
+        clock.spend(secs(20));
+        clock.credit();
+        assert_eq!(clock.periods(), 1);
+        assert_eq!(clock.time(), secs(40));
+        clock.spend(secs(30));
+        clock.credit();
+        //New period
+        assert_eq!(clock.periods(), 2);
+        assert_eq!(clock.time(), secs(60));
+        assert_eq!(clock.time_left(), (60, 2));
+        clock.spend(secs(60));
+        assert_eq!(clock.remaining(), Duration::ZERO);
+    }
+    #[test]
+    fn test_valid() {
+        assert!(TimeControl::Absolute{main: 600}.valid());
+        assert!(!TimeControl::Absolute{main: 0}.valid());
+        assert!(!TimeControl::Fischer{main: 600, increment: 90, cap: Some(60)}.valid());
+        assert!(!TimeControl::Byoyomi{main: 600, periods: 0, period: 30}.valid());
+        assert!(TimeControl::Canadian{main: 0, stones: 25, period: 600}.valid());
+        assert_eq!(TimeControl::Canadian{main: 0, stones: 25, period: 600}.added(), 24);
+    }
+    #[test]
+    fn test_serialize() {
+        let mut clocks = Clocks::new(TimeControl::Byoyomi{main: 0, periods: 3, period: 30});
+        clocks.get_mut(Stone::White).spend(secs(40));
+        clocks.get_mut(Stone::White).resume();
+        let json = serde_json::to_string(&clocks).unwrap();
+        let clocks: Clocks = serde_json::from_str(&json).unwrap();
+        let clock = clocks.get(Stone::White);
+        assert!(!clock.running());
+        assert_eq!(clock.periods(), 2);
+        assert_eq!(clock.time(), secs(20));
+    }
+}
@@ -20,9 +20,12 @@ export class Board extends HTMLCanvasElement {
 	constructor() {
 		super();
 		this.ctx = this.getContext('2d');
-		this.size = 0;
-		this.stones = new Uint8Array(this.size * this.size);
-		this.moves = new Uint8Array(this.size * this.size);
+		this.columns = 0;
+		this.rows = 0;
+		this.stones = new Uint8Array(0);
+		this.moves = new Uint8Array(0);
+		this.lastMove = null;
+		this.dead = new Uint8Array(0);
 		this.cursor = new Cursor(0);
 		this.clickListeners = new Set();
 	}
@@ -33,13 +36,18 @@ export class Board extends HTMLCanvasElement {
 			.then(image => this.tileset = image)
 			.then(() => this.draw());
 	}
-	set board_size(size) {
-		this.size = size;
-		this.stones = new Uint8Array(size * size);
-		this.moves = new Uint8Array(size * size);
+	resize(columns, rows) {
+		this.columns = columns;
+		this.rows = rows;
+		this.stones = new Uint8Array(columns * rows);
+		this.moves = new Uint8Array(columns * rows);
+		this.dead = new Uint8Array(columns * rows);
 		//Canvas
-		this.width = 16 * size;
-		this.height = 16 * size;
+		this.width = 16 * columns;
+		this.height = 16 * rows;
+		//Frame keeps the board's proportions
+		if (this.parentElement)
+			this.parentElement.style.aspectRatio = `${columns} / ${rows}`;
 		this.ctx = this.getContext('2d');
 		this.ctx.imageSmoothingEnabled = false;
 	}
@@ -59,8 +67,8 @@ export class Board extends HTMLCanvasElement {
 	//Event listeners
 	clickListener(event) {
 		if (event.button === 0) {
-			const x = Math.floor(this.size * event.offsetX / this.clientWidth);
-			const y = Math.floor(this.size * event.offsetY / this.clientHeight);
+			const x = Math.floor(this.columns * event.offsetX / this.clientWidth);
+			const y = Math.floor(this.rows * event.offsetY / this.clientHeight);
 			for (const entry of this.clickListeners)
 				entry(x, y);
 			this.draw();
@@ -68,8 +76,8 @@ export class Board extends HTMLCanvasElement {
 	}
 	mousemoveListener(event) {
 		this.cursor.enabled = true;
-		this.cursor.x = Math.floor(this.size * event.offsetX / this.clientWidth);
-		this.cursor.y = Math.floor(this.size * event.offsetY / this.clientHeight);
+		this.cursor.x = Math.floor(this.columns * event.offsetX / this.clientWidth);
+		this.cursor.y = Math.floor(this.rows * event.offsetY / this.clientHeight);
 		this.draw();
 	}
 	mouseoutListener(event) {
@@ -82,38 +90,44 @@ export class Board extends HTMLCanvasElement {
 		this.ctx.translate(0.5, 0.5);
 		//Gray lines
 		this.ctx.strokeStyle = '#202020';
-		for (let i = 0; i < this.size; ++i) {
-			//Vertical
+		//Vertical
+		for (let i = 0; i < this.columns; ++i) {
 			this.ctx.beginPath();
 			this.ctx.moveTo(16 * i + 7, 7);
-			this.ctx.lineTo(16 * i + 7, 16 * this.size - 8);
+			this.ctx.lineTo(16 * i + 7, 16 * this.rows - 8);
 			this.ctx.stroke();
-			//Horizontal
+		}
+		//Horizontal
+		for (let i = 0; i < this.rows; ++i) {
 			this.ctx.beginPath();
 			this.ctx.moveTo(7, 16 * i + 7);
-			this.ctx.lineTo(16 * this.size - 8, 16 * i + 7);
+			this.ctx.lineTo(16 * this.columns - 8, 16 * i + 7);
 			this.ctx.stroke();
 		}
 		//Black lines
 		this.ctx.strokeStyle = '#000000';
-		for (let i = 0; i < this.size; ++i) {
-			//Vertical
+		//Vertical
+		for (let i = 0; i < this.columns; ++i) {
 			this.ctx.beginPath();
 			this.ctx.moveTo(16 * i + 8, 8);
-			this.ctx.lineTo(16 * i + 8, 16 * this.size - 8);
+			this.ctx.lineTo(16 * i + 8, 16 * this.rows - 8);
 			this.ctx.stroke();
-			//Horizontal
+		}
+		//Horizontal
+		for (let i = 0; i < this.rows; ++i) {
 			this.ctx.beginPath();
 			this.ctx.moveTo(8, 16 * i + 8);
-			this.ctx.lineTo(16 * this.size - 8, 16 * i + 8);
+			this.ctx.lineTo(16 * this.columns - 8, 16 * i + 8);
 			this.ctx.stroke();
 		}
 		this.ctx.resetTransform();
 	}
 	drawStones() {
-		for (let y = 0; y < this.size; ++y) {
-			for (let x = 0; x < this.size; ++x) {
-				const stone = this.stones[this.size * y + x];
+		for (let y = 0; y < this.rows; ++y) {
+			for (let x = 0; x < this.columns; ++x) {
+				const stone = this.stones[this.columns * y + x];
+				//Dead stones are faded
+				this.ctx.globalAlpha = this.dead[this.columns * y + x] ? 0.4 : 1;
 				switch (stone) {
 					case 1:
 						this.ctx.drawImage(this.tileset, 0, 0, 8, 8, 16 * x, 16 * y, 16, 16);
@@ -124,9 +138,18 @@ export class Board extends HTMLCanvasElement {
 				}
 			}
 		}
+		this.ctx.globalAlpha = 1;
+	}
+	drawLastMove() {
+		const x = this.lastMove % this.columns;
+		const y = Math.floor(this.lastMove / this.columns);
+		this.ctx.strokeStyle = this.stones[this.lastMove] === 1 ? '#ffffff' : '#000000';
+		this.ctx.beginPath();
+		this.ctx.arc(16 * x + 8, 16 * y + 8, 3, 0, 2 * Math.PI);
+		this.ctx.stroke();
 	}
 	drawCursor() {
-		const index = this.size * this.cursor.y + this.cursor.x;
+		const index = this.columns * this.cursor.y + this.cursor.x;
 		const stone = this.stones[index];
 		const legal = this.moves[index];
 		if (stone === 0 && legal) {
@@ -155,11 +178,16 @@ export class Board extends HTMLCanvasElement {
 			this.ctx.clearRect(0, 0, this.width, this.height);
 			this.drawBoard();
 			this.drawStones();
+			if (this.lastMove !== null) this.drawLastMove();
 			if (this.cursor.enabled) this.drawCursor();
 		}
 	}
 	update(frame) {
+		if (frame.width && (frame.width !== this.columns || frame.height !== this.rows))
+			this.resize(frame.width, frame.height);
 		this.stones = new Uint8Array(frame.board);
 		this.moves = new Uint8Array(frame.moves);
+		this.lastMove = frame.last_move;
+		this.dead = new Uint8Array(frame.dead);
 	}
 }
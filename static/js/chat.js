@@ -0,0 +1,51 @@
+'use strict';
+
+/*
+	Chat messages:
+	{
+		event: 'chat',
+		channel: 'players' | 'spectators',
+		stone: 'black' | 'white' | 'empty',
+		text: 'Hello',
+		time: 0
+	}
+*/
+
+function author(stone) {
+	switch (stone) {
+		case 'black':
+			return 'Black';
+		case 'white':
+			return 'White';
+		default:
+			return 'Spectator';
+	}
+}
+
+//Chat log and form, send is called with the channel and text
+export class Chat {
+	constructor(element, send) {
+		this.log = element.querySelector('.chat-log');
+		this.error = element.querySelector('.chat-error');
+		const form = element.querySelector('form');
+		form.addEventListener('submit', event => {
+			event.preventDefault();
+			const text = form.elements.text.value.trim();
+			if (!text) return;
+			send(form.elements.channel.value, text);
+			form.elements.text.value = '';
+		});
+	}
+	show(message) {
+		const entry = document.createElement('li');
+		const time = new Date(message.time).toLocaleTimeString();
+		const channel = message.channel === 'players' ? ' to players' : '';
+		entry.innerText = `${time} ${author(message.stone)}${channel}: ${message.text}`;
+		this.log.append(entry);
+		this.log.scrollTop = this.log.scrollHeight;
+		this.error.innerText = '';
+	}
+	showError(message) {
+		this.error.innerText = message;
+	}
+}
@@ -1,6 +1,8 @@
 'use strict';
 import {Board} from './board.js';
 import {Timer} from './timer.js';
+import {describe} from './result.js';
+import {Chat} from './chat.js';
 
 //Web components
 window.customElements.define('go-board', Board, {extends: 'canvas'});
@@ -11,7 +13,9 @@ const url = new URL(document.URL);
 const id = document.querySelector('meta[name="go:id"]').content;
 const stoneName = document.querySelector('meta[name="go:stone"]').content;
 const boardSize = parseInt(document.querySelector('meta[name="go:board-size"]').content, 10);
+const boardHeight = parseInt(document.querySelector('meta[name="go:board-height"]').content, 10);
 const handicap = parseInt(document.querySelector('meta[name="go:handicap"]').content, 10);
+const rated = document.querySelector('meta[name="go:rated"]').content === 'true';
 let stone;
 switch (stoneName) {
 	case 'black':
@@ -26,12 +30,13 @@ switch (stoneName) {
 const statusText = document.getElementById('status');
 //Board
 const board = document.getElementById('board');
-board.board_size = boardSize;
+board.resize(boardSize, boardHeight);
 board.cursor.stone = stone;
 board.draw();
 //Buttons
 const handicapButtons = document.getElementById('handicap-buttons');
 const playButtons = document.getElementById('play-buttons');
+const scoringButtons = document.getElementById('scoring-buttons');
 //Timers
 const blackTimer = document.getElementById('black-timer');
 const whiteTimer = document.getElementById('white-timer');
@@ -41,9 +46,34 @@ const blackScore = document.getElementById('black-score');
 const whiteScore = document.getElementById('white-score');
 const scoreStatement = document.getElementById('score-statement');
 score.style.display = 'none';
+//Current count and the scoring engine's suggestion
+function showEstimate(packet) {
+	score.style.display = 'block';
+	blackScore.innerText = packet.black_score;
+	whiteScore.innerText = packet.white_score;
+	scoreStatement.innerText = packet.estimate ? `Suggested: ${describe(packet.estimate)}` : '';
+}
+
+//Hints, only present when an analysis engine is configured
+const hintButtons = document.getElementById('hint-buttons');
+const hintText = document.getElementById('hint');
+function showHint(hint) {
+	const move = hint.move === 'pass' || hint.move === 'resign' ? hint.move : `at ${hint.move}`;
+	hintText.innerText = `Engine suggests ${move}` + (hint.score ? ` (${hint.score})` : '');
+}
+
+//Takebacks, only present when the session allows them
+const undoButtons = document.getElementById('undo-buttons');
+const undoPrompt = document.getElementById('undo-prompt');
+if (undoPrompt) undoPrompt.style.display = 'none';
+let lastPosition;
 
 //Data
 const socket = new WebSocket(`wss://${url.host}/ws/${id}/${stoneName}`);
+//Chat with the opponent, or with everyone watching
+const chat = new Chat(document.getElementById('chat'), (channel, text) => {
+	socket.send(JSON.stringify({action: 'chat', channel, text}));
+});
 let handicaps = [];
 
 function suspend() {
@@ -53,12 +83,14 @@ function suspend() {
 		button.setAttribute('disabled', '');
 	for (const button of playButtons.children)
 		button.setAttribute('disabled', '');
+	for (const button of scoringButtons.children)
+		button.setAttribute('disabled', '');
 	blackTimer.pause();
 	whiteTimer.pause();
 }
 
 function handicapPlacement(x, y) {
-	const index = board.size * y + x;
+	const index = board.columns * y + x;
 	if (board.moves[index] && handicaps.length < handicap) {
 		handicaps.push(index);
 		board.stones[index] = stone;
@@ -70,7 +102,7 @@ function handicapPlacement(x, y) {
 }
 
 function playPlacement(x, y) {
-	const index = board.size * y + x;
+	const index = board.columns * y + x;
 	if (board.moves[index]) {
 		//Play stone
 		board[index] = stone;
@@ -83,6 +115,16 @@ function playPlacement(x, y) {
 	}
 }
 
+function markPlacement(x, y) {
+	const index = board.columns * y + x;
+	if (board.stones[index]) {
+		socket.send(JSON.stringify({
+			action: 'mark',
+			position: index
+		}));
+	}
+}
+
 //Handicap play button
 handicapButtons.children[0].addEventListener('click', event => {
 	socket.send(JSON.stringify({
@@ -114,14 +156,88 @@ playButtons.children[1].addEventListener('click', event => {
 	suspend();
 });
 
+//Accept button
+scoringButtons.children[0].addEventListener('click', event => {
+	socket.send(JSON.stringify({action: 'accept'}));
+	suspend();
+});
+
+//Takeback request and answers
+if (undoButtons) {
+	undoButtons.children[0].addEventListener('click', event => {
+		socket.send(JSON.stringify({action: 'undo'}));
+		undoButtons.children[0].setAttribute('disabled', '');
+	});
+	for (const [index, accept] of [[1, true], [2, false]]) {
+		undoPrompt.children[index].addEventListener('click', event => {
+			socket.send(JSON.stringify({
+				action: 'undo_reply',
+				accept: accept
+			}));
+			undoPrompt.style.display = 'none';
+		});
+	}
+}
+
+//Hint consent and request
+if (hintButtons) {
+	hintButtons.querySelector('input').addEventListener('change', event => {
+		socket.send(JSON.stringify({
+			action: 'allow_hints',
+			allow: event.target.checked
+		}));
+	});
+	hintButtons.querySelector('button').addEventListener('click', event => {
+		hintText.innerText = 'Thinking...';
+		socket.send(JSON.stringify({action: 'hint'}));
+	});
+}
+
 //WebSocket events
 socket.addEventListener('message', event => {
-	suspend();
 	const frame = JSON.parse(event.data);
 	//console.log(frame);
+	//Replies to this player's hint requests
+	if (frame.event === 'hint') {
+		showHint(frame);
+		return;
+	}
+	if (frame.event === 'hint-error') {
+		hintText.innerText = frame.message;
+		return;
+	}
+	//Takeback requests are answered by the opponent
+	if (frame.event === 'undo-request') {
+		if (undoPrompt && frame.stone !== stoneName) {
+			undoPrompt.children[0].innerText = `${frame.stone === 'black' ? 'Black' : 'White'} asks to take back a move`;
+			undoPrompt.style.display = 'block';
+		}
+		return;
+	}
+	if (frame.event === 'undo-declined') {
+		if (frame.stone === stoneName) statusText.innerText = 'Takeback declined';
+		return;
+	}
+	if (frame.event === 'dead-suggested') {
+		statusText.innerText = 'Dead stones marked by the engine, check them before accepting';
+		return;
+	}
+	if (frame.event === 'chat') {
+		chat.show(frame);
+		return;
+	}
+	if (frame.event === 'chat-error') {
+		chat.showError(frame.message);
+		return;
+	}
+	suspend();
+	if (frame.event === 'shutdown') {
+		statusText.innerText = 'Server shutting down, the game will resume after restart';
+		return;
+	}
 	board.update(frame);
-	blackTimer.update(frame.black_time);
-	whiteTimer.update(frame.white_time);
+	blackTimer.update(frame.black_time, frame.black_periods);
+	whiteTimer.update(frame.white_time, frame.white_periods);
 	switch (frame.turn) {
 		case 'wait':
 			statusText.innerText = 'Waiting for players';
@@ -140,16 +256,18 @@ socket.addEventListener('message', event => {
 			statusText.innerText = 'White to play'
 			whiteTimer.resume();
 			break;
+		case 'scoring':
+			if (frame.black_accepted || frame.white_accepted)
+				statusText.innerText = `${frame.black_accepted ? 'Black' : 'White'} accepted the score`;
+			else statusText.innerText = 'Mark dead stones';
+			showEstimate(frame);
+			break;
 		case 'end':
 			statusText.innerText = 'Game over'
 			score.style.display = 'block';
 			blackScore.innerText = frame.black_score;
 			whiteScore.innerText = frame.white_score;
-			if (frame.black_score > frame.white_score)
-				scoreStatement.innerText = `Black wins by +${frame.black_score - frame.white_score}`;
-			else if (frame.white_score > frame.black_score)
-				scoreStatement.innerText = `White wins by +${frame.white_score - frame.black_score}`;
-			else scoreStatement.innerText = 'Draw';
+			scoreStatement.innerText = describe(frame.result);
 			break;
 	}
 	//Interactions
@@ -157,6 +275,12 @@ socket.addEventListener('message', event => {
 		//Handicap
 		board.enabled = true;
 		board.clickListeners.add(handicapPlacement);
+	} else if (frame.turn === 'scoring') {
+		//Scoring
+		board.enabled = true;
+		board.clickListeners.add(markPlacement);
+		const accepted = stone === 1 ? frame.black_accepted : frame.white_accepted;
+		if (!accepted) scoringButtons.children[0].removeAttribute('disabled');
 	} else if (stoneName === frame.turn) {
 		//Play
 		board.enabled = true;
@@ -164,6 +288,23 @@ socket.addEventListener('message', event => {
 		for (const button of playButtons.children)
 			button.removeAttribute('disabled');
 	}
+	if (undoButtons) {
+		//Any move or takeback ends a pending request
+		const position = JSON.stringify([frame.board, frame.turn]);
+		if (position !== lastPosition) undoPrompt.style.display = 'none';
+		lastPosition = position;
+		const playing = frame.turn === 'black' || frame.turn === 'white';
+		if (playing) undoButtons.children[0].removeAttribute('disabled');
+		else undoButtons.children[0].setAttribute('disabled', '');
+	}
+	if (hintButtons) {
+		const allow = hintButtons.querySelector('input');
+		allow.checked = stone === 1 ? frame.black_hints : frame.white_hints;
+		allow.disabled = frame.turn === 'end';
+		//Hints need both players' consent in casual games, or the game to be over
+		const allowed = frame.turn === 'end' || !rated && frame.black_hints && frame.white_hints;
+		hintButtons.querySelector('button').disabled = !allowed;
+	}
 	board.draw();
 });
 socket.addEventListener('error', event => {
@@ -1,6 +1,8 @@
 'use strict';
 import {Board} from './board.js';
 import {Timer} from './timer.js';
+import {describe} from './result.js';
+import {Chat} from './chat.js';
 
 //Web components
 window.customElements.define('go-board', Board, {extends: 'canvas'});
@@ -8,13 +10,14 @@ window.customElements.define('go-timer', Timer, {extends: 'span'});
 
 //Metadata
 const id = document.querySelector('meta[name="go:id"]').content;
-const board_size = document.querySelector('meta[name="go:board-size"]').content;
+const board_size = parseInt(document.querySelector('meta[name="go:board-size"]').content, 10);
+const board_height = parseInt(document.querySelector('meta[name="go:board-height"]').content, 10);
 
 //UI elements
 const statusText = document.getElementById('status');
 //Board
 const board = document.getElementById('board');
-board.board_size = board_size;
+board.resize(board_size, board_height);
 board.draw();
 //Buttons
 const joinButtons = document.getElementById('join-buttons');
@@ -28,20 +31,51 @@ const blackScore = document.getElementById('black-score');
 const whiteScore = document.getElementById('white-score');
 const scoreStatement = document.getElementById('score-statement');
 score.style.display = 'none';
+//Current count and the scoring engine's suggestion
+function showEstimate(packet) {
+	score.style.display = 'block';
+	blackScore.innerText = packet.black_score;
+	whiteScore.innerText = packet.white_score;
+	scoreStatement.innerText = packet.estimate ? `Suggested: ${describe(packet.estimate)}` : '';
+}
+
+//Chat, spectators write over a WebSocket and read through SSE
+const url = new URL(document.URL);
+const socket = new WebSocket(`wss://${url.host}/ws/${id}/spectator`);
+const chat = new Chat(document.getElementById('chat'), (channel, text) => {
+	socket.send(JSON.stringify({action: 'chat', channel, text}));
+});
+socket.addEventListener('message', event => {
+	const data = JSON.parse(event.data);
+	if (data.event === 'chat-error') chat.showError(data.message);
+});
 
 //SSE
 const eventSource = new EventSource(`/sse/${id}`);
 eventSource.addEventListener('message', event => {
 	const data = JSON.parse(event.data);
 	//console.log(data);
+	if (data.event === 'chat') {
+		chat.show(data);
+		return;
+	}
+	if (data.event === 'shutdown') {
+		statusText.innerText = 'Server shutting down';
+		blackTimer.pause();
+		whiteTimer.pause();
+		eventSource.close();
+		return;
+	}
+	//Takeback requests are only for the players
+	if (data.event !== 'packet') return;
 	//Board
 	board.update(data);
 	board.draw();
 	//Timers
 	blackTimer.pause();
 	whiteTimer.pause();
-	blackTimer.update(data.black_time);
-	whiteTimer.update(data.white_time);
+	blackTimer.update(data.black_time, data.black_periods);
+	whiteTimer.update(data.white_time, data.white_periods);
 	switch (data.turn) {
 		case 'wait':
 			statusText.innerText = 'Waiting for players';
@@ -58,18 +92,18 @@ eventSource.addEventListener('message', event => {
 			break;
 		case 'white':
 			statusText.innerText = 'White to play';
-			whiteTimer.update(data.white_time);
+			whiteTimer.resume();
+			break;
+		case 'scoring':
+			statusText.innerText = 'Scoring';
+			showEstimate(data);
 			break;
 		case 'end':
 			statusText.innerText = 'Game over';
 			score.style.display = 'block';
 			blackScore.innerText = data.black_score;
 			whiteScore.innerText = data.white_score;
-			if (data.black_score > data.white_score)
-				scoreStatement.innerText = `Black wins by +${data.black_score - data.white_score}`;
-			else if (data.white_score > data.black_score)
-				scoreStatement.innerText = `White wins by +${data.white_score - data.black_score}`;
-			else scoreStatement.innerText = 'Draw';
+			scoreStatement.innerText = describe(data.result);
 			eventSource.close();
 			break;
 	}
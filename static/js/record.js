@@ -0,0 +1,43 @@
+'use strict';
+import {Board} from './board.js';
+import {describe} from './result.js';
+
+//Web components
+window.customElements.define('go-board', Board, {extends: 'canvas'});
+
+//Metadata
+const id = document.querySelector('meta[name="go:id"]').content;
+const boardSize = parseInt(document.querySelector('meta[name="go:board-size"]').content, 10);
+const boardHeight = parseInt(document.querySelector('meta[name="go:board-height"]').content, 10);
+const created = parseInt(document.querySelector('meta[name="go:created"]').content, 10);
+const frame = JSON.parse(document.getElementById('frame').textContent);
+const result = JSON.parse(document.getElementById('result').textContent);
+
+//Board
+const board = document.getElementById('board');
+board.resize(boardSize, boardHeight);
+board.update(frame);
+board.draw();
+
+//Details
+document.getElementById('created').innerText = new Date(created).toLocaleString();
+document.getElementById('status').innerText = result ? 'Game over' : 'Game in progress';
+if (result)
+	document.getElementById('score-statement').innerText = describe(result);
+
+//Hint for the final position
+const hintButtons = document.getElementById('hint-buttons');
+if (hintButtons) {
+	const hintText = document.getElementById('hint');
+	hintButtons.children[0].addEventListener('click', async event => {
+		hintText.innerText = 'Thinking...';
+		const response = await fetch(`/hint/${id}`);
+		if (!response.ok) {
+			hintText.innerText = await response.text();
+			return;
+		}
+		const hint = await response.json();
+		const move = hint.move === 'pass' || hint.move === 'resign' ? hint.move : `at ${hint.move}`;
+		hintText.innerText = `Engine suggests ${move}` + (hint.score ? ` (${hint.score})` : '');
+	});
+}
@@ -0,0 +1,26 @@
+'use strict';
+
+/*
+	Results:
+	score, draw, resignation, timeout, forfeit, aborted, engine-failure
+*/
+
+export function describe(result) {
+	const winner = result.winner === 'black' ? 'Black' : 'White';
+	switch (result.reason) {
+		case 'score':
+			return `${winner} wins by ${result.margin}`;
+		case 'draw':
+			return 'Draw';
+		case 'resignation':
+			return `${winner} wins by resignation`;
+		case 'timeout':
+			return `${winner} wins on time`;
+		case 'forfeit':
+			return `${winner} wins by forfeit`;
+		case 'aborted':
+			return 'Game aborted';
+		case 'engine-failure':
+			return `Game ended: ${result.stone === 'black' ? 'Black' : 'White'} engine failed`;
+	}
+}
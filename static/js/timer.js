@@ -4,6 +4,7 @@ export class Timer extends HTMLSpanElement {
 	constructor() {
 		super();
 		this.time = 0;
+		this.periods = 0;
 	}
 	connectedCallback() {
 		this.innerText = this.display;
@@ -13,13 +14,16 @@ export class Timer extends HTMLSpanElement {
 		const seconds = this.time % 60;
 		let minText = minutes >= 10 ? minutes.toString() : '0' + minutes.toString();
 		let secText = seconds >= 10 ? seconds.toString() : '0' + seconds.toString();
-		return `${minText}:${secText}`;
+		//Byo-yomi periods or Canadian stones left
+		const periodText = this.periods > 0 ? ` (${this.periods})` : '';
+		return `${minText}:${secText}${periodText}`;
 	}
 	get running() {
 		return Boolean(this.interval);
 	}
-	update(time) {
+	update(time, periods = 0) {
 		this.time = time;
+		this.periods = periods;
 		this.innerText = this.display;
 	}
 	resume() {